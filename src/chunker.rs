@@ -0,0 +1,173 @@
+//! Content defined chunking of large file data
+//!
+//! This is a FastCDC style chunker: a gear based rolling hash is computed
+//! over the data, and a chunk boundary is declared wherever the top bits of
+//! the hash are all zero.  Before the average chunk size a stricter mask is
+//! used, and after it a looser mask, which normalises chunk sizes around the
+//! average.  Since boundaries depend only on the data near them, an insertion
+//! or deletion in a file only disturbs the chunks around it.
+
+/// Configuration for splitting large files into content defined chunks
+///
+/// Files larger than the maximum chunk size are split into chunks which are
+/// stored individually, allowing content to be shared between similar files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkingConfig {
+    /// No chunk, other than the last in a file, will be smaller than this
+    pub min_size: usize,
+    /// The size chunks are normalised around, rounded to a power of two
+    pub avg_size: usize,
+    /// No chunk will be larger than this, and files no larger than this
+    /// are not chunked at all
+    pub max_size: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 256 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    /// Whether or not a file of the given size would be chunked
+    pub(crate) fn chunks(&self, size: usize) -> bool {
+        size > self.max_size
+    }
+
+    // Ord::clamp needs a newer compiler than the rest of the crate does
+    #[allow(clippy::manual_clamp)]
+    pub(crate) fn chunker(&self) -> Chunker {
+        let bits = self.avg_size.next_power_of_two().trailing_zeros();
+        let mask = |bits: u32| !0u64 << (64 - bits.min(63).max(1));
+        Chunker {
+            min_size: self.min_size.min(self.max_size),
+            avg_size: self.avg_size.min(self.max_size),
+            max_size: self.max_size.max(1),
+            mask_small: mask(bits + 2),
+            mask_large: mask(bits.saturating_sub(2)),
+        }
+    }
+}
+
+/// Finds chunk boundaries in data, see [`ChunkingConfig`]
+pub(crate) struct Chunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl Chunker {
+    /// The largest chunk this chunker will ever produce
+    pub(crate) fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Find the length of the first chunk at the start of the data
+    ///
+    /// If no boundary is found, and there is less than the maximum chunk
+    /// size of data, then None is returned since more data may be needed to
+    /// find the boundary.  At the end of the data, the remainder of the data
+    /// forms the final chunk.
+    pub(crate) fn boundary(&self, data: &[u8]) -> Option<usize> {
+        let limit = data.len().min(self.max_size);
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(limit).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if i < self.avg_size {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+            if hash & mask == 0 {
+                return Some(i + 1);
+            }
+        }
+        if limit == self.max_size {
+            Some(limit)
+        } else {
+            None
+        }
+    }
+
+    /// Split data which is entirely in memory into chunks
+    #[cfg(test)]
+    pub(crate) fn split<'a>(&'a self, mut data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        std::iter::from_fn(move || {
+            if data.is_empty() {
+                return None;
+            }
+            let len = self.boundary(data).unwrap_or(data.len());
+            let (chunk, rest) = data.split_at(len);
+            data = rest;
+            Some(chunk)
+        })
+    }
+}
+
+/// The gear table, a fixed set of pseudo-random values.  These must never
+/// change or the chunk boundaries of new imports will not match old ones.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, seeded arbitrarily
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5348_4152_4544_5354;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn boundaries_resynchronise() {
+        let config = ChunkingConfig {
+            min_size: 1024,
+            avg_size: 4096,
+            max_size: 16384,
+        };
+        let chunker = config.chunker();
+        let data = pseudo_random(256 * 1024, 1);
+        let chunks: Vec<_> = chunker.split(&data).collect();
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+        assert!(chunks.len() > 16);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= config.min_size && chunk.len() <= config.max_size);
+        }
+
+        // Insert some bytes near the start, most chunks should be unaffected
+        let mut edited = data[..5000].to_vec();
+        edited.extend_from_slice(b"an insertion");
+        edited.extend_from_slice(&data[5000..]);
+        let edited_chunks: Vec<_> = chunker.split(&edited).collect();
+        let shared = edited_chunks.iter().filter(|c| chunks.contains(c)).count();
+        assert!(shared >= chunks.len() - 3);
+    }
+}
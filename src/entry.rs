@@ -4,27 +4,203 @@
 use fehler::{throw, throws};
 use serde::{Deserialize, Serialize};
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::hash_map::{self, Entry};
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::default::Default;
 use std::ffi::{OsStr, OsString};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::storage::StorageIdentifier;
+use crate::storage::{os_str_bytes, StorageIdentifier};
 use crate::Error;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum DirectoryEntry {
     Directory(Directory),
     File(StorageIdentifier),
+    /// A symbolic link, with the target it points at
+    Symlink(OsString),
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
 pub struct Directory {
+    #[serde(with = "entries")]
     entries: HashMap<OsString, DirectoryEntry>,
 }
 
+/// Names are not necessarily valid strings, so they cannot be used as keys
+/// when serialised.  Instead the entries are serialised as a list of pairs.
+mod entries {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+
+    use super::DirectoryEntry;
+
+    pub fn serialize<S: Serializer>(
+        entries: &HashMap<OsString, DirectoryEntry>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(entries)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<OsString, DirectoryEntry>, D::Error> {
+        let entries = Vec::<(OsString, DirectoryEntry)>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// A single directory as it is stored in the storage, with its
+/// subdirectories referred to by their digests
+///
+/// The entries are sorted by name, so that the digest is stable.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TreeNode {
+    entries: Vec<(OsString, TreeEntry)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum TreeEntry {
+    Directory(String),
+    File(StorageIdentifier),
+    Symlink(OsString),
+}
+
+impl TreeNode {
+    /// The digest of this directory, the SHA-256 of a canonical encoding of
+    /// its entries, in lower case hex
+    pub(crate) fn digest(&self) -> String {
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        let mut input_bytes = |tag: &[u8], bytes: &[u8]| {
+            hasher.input(tag);
+            hasher.input((bytes.len() as u64).to_le_bytes());
+            hasher.input(bytes);
+        };
+        for (name, entry) in &self.entries {
+            input_bytes(b"n", &os_str_bytes(name));
+            match entry {
+                TreeEntry::Directory(digest) => input_bytes(b"d", digest.as_bytes()),
+                TreeEntry::File(identity) => {
+                    let tag = if identity.executable() { b"x" } else { b"f" };
+                    input_bytes(tag, identity.hash().as_bytes());
+                    input_bytes(b"s", &(identity.size() as u64).to_le_bytes());
+                }
+                TreeEntry::Symlink(target) => input_bytes(b"l", &os_str_bytes(target)),
+            }
+        }
+        format!("{:x}", hasher.result())
+    }
+}
+
+/// A difference between two directories, see [`Directory::diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// An entry present only in the new directory
+    Added { path: PathBuf, new: ChangedEntry },
+    /// An entry present only in the old directory
+    Removed { path: PathBuf, old: ChangedEntry },
+    /// A file whose content or executable bit changed, or a symbolic link
+    /// whose target changed
+    Modified {
+        path: PathBuf,
+        old: ChangedEntry,
+        new: ChangedEntry,
+    },
+    /// An entry which changed between being a file, a directory and a
+    /// symbolic link
+    TypeChanged {
+        path: PathBuf,
+        old: ChangedEntry,
+        new: ChangedEntry,
+    },
+}
+
+/// An entry in a directory, as reported in a [`Change`], which leaves out
+/// the content of subdirectories
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangedEntry {
+    Directory,
+    File(StorageIdentifier),
+    Symlink(OsString),
+}
+
+impl Change {
+    /// The path of the entry which changed, relative to the directories
+    /// being compared
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Added { path, .. }
+            | Change::Removed { path, .. }
+            | Change::Modified { path, .. }
+            | Change::TypeChanged { path, .. } => path,
+        }
+    }
+
+    /// The entry in the old directory, unless it was added
+    pub fn old_entry(&self) -> Option<&ChangedEntry> {
+        match self {
+            Change::Added { .. } => None,
+            Change::Removed { old, .. }
+            | Change::Modified { old, .. }
+            | Change::TypeChanged { old, .. } => Some(old),
+        }
+    }
+
+    /// The entry in the new directory, unless it was removed
+    pub fn new_entry(&self) -> Option<&ChangedEntry> {
+        match self {
+            Change::Removed { .. } => None,
+            Change::Added { new, .. }
+            | Change::Modified { new, .. }
+            | Change::TypeChanged { new, .. } => Some(new),
+        }
+    }
+}
+
+impl ChangedEntry {
+    /// The content of the entry, if it is a file
+    pub fn identity(&self) -> Option<&StorageIdentifier> {
+        match self {
+            ChangedEntry::File(identity) => Some(identity),
+            _ => None,
+        }
+    }
+}
+
+impl From<&DirectoryEntry> for ChangedEntry {
+    fn from(entry: &DirectoryEntry) -> Self {
+        match entry {
+            DirectoryEntry::Directory(_) => ChangedEntry::Directory,
+            DirectoryEntry::File(identity) => ChangedEntry::File(identity.clone()),
+            DirectoryEntry::Symlink(target) => ChangedEntry::Symlink(target.clone()),
+        }
+    }
+}
+
+/// How to resolve conflicting entries when merging directories together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Conflicting entries are an error, exactly as when inserting entries
+    /// during an import.  Identical files and directories are merged.
+    FailOnConflict,
+    /// The entry which was merged in first is kept
+    FirstWins,
+    /// The entry which was merged in last replaces any earlier entry
+    LastWins,
+}
+
+// #[default] on enum variants needs a newer compiler than the rest of the crate does
+#[allow(clippy::derivable_impls)]
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy::FailOnConflict
+    }
+}
+
 impl Directory {
     #[throws(Error)]
     fn descend<C: AsRef<OsStr>>(&self, component: C) -> &Directory {
@@ -92,6 +268,41 @@ impl Directory {
         here
     }
 
+    /// Find the storage identifier of the file at the given path
+    #[throws(Error)]
+    pub fn file<P: AsRef<Path>>(&self, path: P) -> &StorageIdentifier {
+        let path = path.as_ref();
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name,
+            None => {
+                self.traverse(path)?;
+                throw!(Error::EntryIsDirectory(path.into()))
+            }
+        };
+        let parent = self.traverse(path.parent().unwrap_or_else(|| Path::new("")))?;
+        match parent.entries.get(file_name) {
+            None => throw!(Error::EntryNotFound(file_name.into())),
+            Some(DirectoryEntry::Directory(_)) => throw!(Error::EntryIsDirectory(path.into())),
+            Some(DirectoryEntry::Symlink(_)) => throw!(Error::EntryIsSymlink(path.into())),
+            Some(DirectoryEntry::File(identity)) => identity,
+        }
+    }
+
+    /// Remove the entry at the given path, along with anything beneath it
+    #[throws(Error)]
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> DirectoryEntry {
+        let path = path.as_ref();
+        let file_name = match path.file_name() {
+            Some(file_name) => file_name,
+            None => throw!(Error::EntryNotFound(path.into())),
+        };
+        let parent = self.traverse_mut(path.parent().unwrap_or_else(|| Path::new("")), false)?;
+        match parent.entries.remove(file_name) {
+            Some(entry) => entry,
+            None => throw!(Error::EntryNotFound(path.into())),
+        }
+    }
+
     #[throws(Error)]
     pub fn insert_file<S: Into<OsString>>(&mut self, file_name: S, identity: StorageIdentifier) {
         let file_name = file_name.into();
@@ -106,6 +317,36 @@ impl Directory {
                 DirectoryEntry::File(f) if f != &identity => {
                     throw!(Error::FileEntryExistsAsFile(v.key().into()))
                 }
+                DirectoryEntry::Symlink(_) => {
+                    throw!(Error::FileEntryExistsAsSymlink(v.key().into()))
+                }
+                _ => {}
+            },
+        }
+    }
+
+    #[throws(Error)]
+    pub fn insert_symlink<S: Into<OsString>, T: Into<OsString>>(
+        &mut self,
+        file_name: S,
+        target: T,
+    ) {
+        let file_name = file_name.into();
+        let target = target.into();
+        match self.entries.entry(file_name) {
+            Entry::Vacant(v) => {
+                v.insert(DirectoryEntry::Symlink(target));
+            }
+            Entry::Occupied(v) => match v.get() {
+                DirectoryEntry::Directory(_) => {
+                    throw!(Error::SymlinkEntryExistsAsDirectory(v.key().into()))
+                }
+                DirectoryEntry::File(_) => {
+                    throw!(Error::SymlinkEntryExistsAsFile(v.key().into()))
+                }
+                DirectoryEntry::Symlink(t) if t != &target => {
+                    throw!(Error::SymlinkEntryExistsAsSymlink(v.key().into()))
+                }
                 _ => {}
             },
         }
@@ -122,14 +363,285 @@ impl Directory {
                 DirectoryEntry::File(_) => {
                     throw!(Error::DirectoryEntryExistsAsFile(v.key().into()))
                 }
-                _ => {}
+                DirectoryEntry::Symlink(_) => {
+                    throw!(Error::DirectoryEntryExistsAsSymlink(v.key().into()))
+                }
+                DirectoryEntry::Directory(_) => {}
             },
         }
     }
 
+    /// A copy of this directory with only its subdirectories, all the way
+    /// down, and none of its files or symbolic links
+    pub(crate) fn skeleton(&self) -> Directory {
+        let entries = self
+            .entries
+            .iter()
+            .filter_map(|(name, entry)| match entry {
+                DirectoryEntry::Directory(dir) => {
+                    Some((name.clone(), DirectoryEntry::Directory(dir.skeleton())))
+                }
+                _ => None,
+            })
+            .collect();
+        Directory { entries }
+    }
+
+    /// Merge the content of another directory into this one
+    ///
+    /// Directories present in both are merged recursively, anything else
+    /// which is present in both is resolved according to the policy.
+    #[throws(Error)]
+    pub fn merge(&mut self, other: &Directory, policy: MergePolicy) {
+        for (name, theirs) in &other.entries {
+            match self.entries.entry(name.clone()) {
+                Entry::Vacant(v) => {
+                    v.insert(theirs.clone());
+                }
+                Entry::Occupied(mut v) => match (v.get_mut(), theirs) {
+                    (DirectoryEntry::Directory(ours), DirectoryEntry::Directory(theirs)) => {
+                        ours.merge(theirs, policy)?
+                    }
+                    (ours, theirs) if *ours == *theirs => {}
+                    (ours, theirs) => match policy {
+                        MergePolicy::FailOnConflict => throw!(conflict(name, ours, theirs)),
+                        MergePolicy::FirstWins => {}
+                        MergePolicy::LastWins => *ours = theirs.clone(),
+                    },
+                },
+            }
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
     }
+
+    /// Iterate the entries of this directory, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&OsStr, &DirectoryEntry)> {
+        self.entries.iter().map(|(k, v)| (k.as_os_str(), v))
+    }
+
+    /// Look up the entry at the given path
+    ///
+    /// This returns None if there is no such entry, including if the path is
+    /// for this directory itself or could not be traversed.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&DirectoryEntry> {
+        let path = path.as_ref();
+        let file_name = path.file_name()?;
+        self.traverse(path.parent()?).ok()?.entries.get(file_name)
+    }
+
+    /// The content digest of this directory
+    ///
+    /// The digest depends only on the names and content of the entries
+    /// beneath the directory, so two directories are equal exactly when their
+    /// digests are.
+    pub fn digest(&self) -> String {
+        self.to_tree(&mut |_, _| {})
+    }
+
+    /// Break this directory down into tree nodes, deepest first, passing
+    /// each to the callback with its digest, and returning the root digest
+    pub(crate) fn to_tree<F>(&self, nodes: &mut F) -> String
+    where
+        F: FnMut(&str, &TreeNode),
+    {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(name, entry)| {
+                let entry = match entry {
+                    DirectoryEntry::Directory(d) => TreeEntry::Directory(d.to_tree(nodes)),
+                    DirectoryEntry::File(identity) => TreeEntry::File(identity.clone()),
+                    DirectoryEntry::Symlink(target) => TreeEntry::Symlink(target.clone()),
+                };
+                (name.clone(), entry)
+            })
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let node = TreeNode { entries };
+        let digest = node.digest();
+        nodes(&digest, &node);
+        digest
+    }
+
+    /// Reassemble a directory from the tree node with the given digest,
+    /// loading nodes with the given function
+    ///
+    /// Every node loaded is checked against its digest.
+    #[throws(Error)]
+    pub(crate) fn from_tree<F>(digest: &str, load: &mut F) -> Directory
+    where
+        F: FnMut(&str) -> Result<TreeNode, Error>,
+    {
+        let node = load(digest)?;
+        if node.digest() != digest {
+            throw!(Error::CorruptTree(digest.into()));
+        }
+        let mut dir = Directory::default();
+        for (name, entry) in node.entries {
+            let entry = match entry {
+                TreeEntry::Directory(d) => {
+                    DirectoryEntry::Directory(Directory::from_tree(&d, load)?)
+                }
+                TreeEntry::File(identity) => DirectoryEntry::File(identity),
+                TreeEntry::Symlink(target) => DirectoryEntry::Symlink(target),
+            };
+            dir.entries.insert(name, entry);
+        }
+        dir
+    }
+
+    /// Recursively walk every file beneath this directory
+    ///
+    /// The paths yielded are relative to this directory, and are yielded in
+    /// no particular order.
+    pub fn walk(&self) -> Walk<'_> {
+        Walk {
+            stack: vec![(PathBuf::new(), self.entries.iter())],
+        }
+    }
+
+    /// Compare this directory, as the old one, with another
+    ///
+    /// Changes are yielded in order of their paths, with the change to a
+    /// directory coming before any changes beneath it.  Everything beneath a
+    /// directory which was added or removed, including by changing type, is
+    /// reported as added or removed along with it.  A directory whose
+    /// content changed is not itself reported as changed.
+    pub fn diff<'a>(&'a self, other: &'a Directory) -> Diff<'a> {
+        Diff {
+            stack: vec![(PathBuf::new(), paired_entries(Some(self), Some(other)))],
+        }
+    }
+}
+
+/// The entries of two directories, paired up by name and sorted by name
+type PairedEntries<'a> = std::vec::IntoIter<(
+    &'a OsStr,
+    Option<&'a DirectoryEntry>,
+    Option<&'a DirectoryEntry>,
+)>;
+
+fn paired_entries<'a>(old: Option<&'a Directory>, new: Option<&'a Directory>) -> PairedEntries<'a> {
+    let mut pairs: BTreeMap<&OsStr, (Option<_>, Option<_>)> = BTreeMap::new();
+    for (name, entry) in old.iter().flat_map(|dir| dir.iter()) {
+        pairs.entry(name).or_default().0 = Some(entry);
+    }
+    for (name, entry) in new.iter().flat_map(|dir| dir.iter()) {
+        pairs.entry(name).or_default().1 = Some(entry);
+    }
+    pairs
+        .into_iter()
+        .map(|(name, (old, new))| (name, old, new))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+/// The error raised when an entry conflicts with one already present
+fn conflict(name: &OsStr, ours: &DirectoryEntry, theirs: &DirectoryEntry) -> Error {
+    use DirectoryEntry::*;
+    let name = name.into();
+    match (ours, theirs) {
+        (Directory(_), Symlink(_)) => Error::SymlinkEntryExistsAsDirectory(name),
+        (Directory(_), _) => Error::FileEntryExistsAsDirectory(name),
+        (File(_), Directory(_)) => Error::DirectoryEntryExistsAsFile(name),
+        (File(_), File(_)) => Error::FileEntryExistsAsFile(name),
+        (File(_), Symlink(_)) => Error::SymlinkEntryExistsAsFile(name),
+        (Symlink(_), Directory(_)) => Error::DirectoryEntryExistsAsSymlink(name),
+        (Symlink(_), File(_)) => Error::FileEntryExistsAsSymlink(name),
+        (Symlink(_), Symlink(_)) => Error::SymlinkEntryExistsAsSymlink(name),
+    }
+}
+
+/// An iterator over every file beneath a directory, see [`Directory::walk`]
+pub struct Walk<'a> {
+    stack: Vec<(PathBuf, hash_map::Iter<'a, OsString, DirectoryEntry>)>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = (PathBuf, &'a StorageIdentifier);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (prefix, entries) = self.stack.last_mut()?;
+            match entries.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some((name, DirectoryEntry::Directory(d))) => {
+                    let path = prefix.join(name);
+                    self.stack.push((path, d.entries.iter()));
+                }
+                Some((name, DirectoryEntry::File(identity))) => {
+                    return Some((prefix.join(name), identity));
+                }
+                Some((_, DirectoryEntry::Symlink(_))) => {}
+            }
+        }
+    }
+}
+
+/// An iterator over the changes between two directories, see
+/// [`Directory::diff`]
+pub struct Diff<'a> {
+    stack: Vec<(PathBuf, PairedEntries<'a>)>,
+}
+
+impl<'a> Iterator for Diff<'a> {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use DirectoryEntry::*;
+        loop {
+            let (prefix, entries) = self.stack.last_mut()?;
+            let (name, old, new) = match entries.next() {
+                Some(pair) => pair,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            if old == new {
+                continue;
+            }
+            let path = prefix.join(name);
+            let subdir = |entry: Option<&'a DirectoryEntry>| match entry {
+                Some(Directory(d)) => Some(d),
+                _ => None,
+            };
+            let (old_dir, new_dir) = (subdir(old), subdir(new));
+            if old_dir.is_some() || new_dir.is_some() {
+                let entries = paired_entries(old_dir, new_dir);
+                self.stack.push((path.clone(), entries));
+            }
+            let change = match (old, new) {
+                (None, Some(new)) => Change::Added {
+                    path,
+                    new: new.into(),
+                },
+                (Some(old), None) => Change::Removed {
+                    path,
+                    old: old.into(),
+                },
+                (Some(Directory(_)), Some(Directory(_))) => continue,
+                (Some(old @ File(_)), Some(new @ File(_)))
+                | (Some(old @ Symlink(_)), Some(new @ Symlink(_))) => Change::Modified {
+                    path,
+                    old: old.into(),
+                    new: new.into(),
+                },
+                (Some(old), Some(new)) => Change::TypeChanged {
+                    path,
+                    old: old.into(),
+                    new: new.into(),
+                },
+                (None, None) => unreachable!("every name is in one of the directories"),
+            };
+            return Some(change);
+        }
+    }
 }
 
 impl TryFrom<&str> for Directory {
@@ -155,4 +667,236 @@ mod test {
         let dir = Directory::default();
         assert!(dir.is_empty());
     }
+
+    #[test]
+    fn serialise_roundtrip() {
+        let mut dir = Directory::default();
+        dir.traverse_mut("sub", true)
+            .unwrap()
+            .insert_file("file", StorageIdentifier::new("aaaa".into(), 1, true))
+            .unwrap();
+        dir.insert_symlink("link", "sub/file").unwrap();
+        let serialised = String::try_from(&dir).unwrap();
+        assert_eq!(Directory::try_from(serialised.as_ref()).unwrap(), dir);
+    }
+
+    #[test]
+    fn tree_digests() {
+        let ident = |hash: &str| StorageIdentifier::new(hash.into(), 1, false);
+        let mut one = Directory::default();
+        one.traverse_mut("a/b", true)
+            .unwrap()
+            .insert_file("file", ident("aaaa"))
+            .unwrap();
+        one.insert_symlink("link", "a").unwrap();
+        let mut two = Directory::default();
+        two.insert_symlink("link", "a").unwrap();
+        two.mkdir("a").unwrap();
+        two.traverse_mut("a", false).unwrap().mkdir("b").unwrap();
+        two.traverse_mut("a/b", false)
+            .unwrap()
+            .insert_file("file", ident("aaaa"))
+            .unwrap();
+        assert_eq!(one.digest(), two.digest());
+        assert_eq!(
+            one.traverse("a").unwrap().digest(),
+            two.traverse("a").unwrap().digest()
+        );
+        two.insert_file("extra", ident("bbbb")).unwrap();
+        assert_ne!(one.digest(), two.digest());
+        assert_eq!(
+            one.traverse("a").unwrap().digest(),
+            two.traverse("a").unwrap().digest()
+        );
+
+        let mut nodes = HashMap::new();
+        let digest = two.to_tree(&mut |digest, node| {
+            let encoded = bincode::serialize(node).unwrap();
+            nodes.insert(digest.to_owned(), encoded);
+        });
+        assert_eq!(nodes.len(), 3);
+        let mut load = |digest: &str| Ok(bincode::deserialize(&nodes[digest]).unwrap());
+        assert_eq!(Directory::from_tree(&digest, &mut load).unwrap(), two);
+        let wrong = one.traverse("a").unwrap().digest();
+        let mut load = |_: &str| Ok(bincode::deserialize(&nodes[&wrong]).unwrap());
+        assert!(matches!(
+            Directory::from_tree(&digest, &mut load),
+            Err(Error::CorruptTree(_))
+        ));
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        let ident = |hash: &str| StorageIdentifier::new(hash.into(), 1, false);
+        let mut one = Directory::default();
+        one.mkdir("sub").unwrap();
+        one.traverse_mut("sub", false)
+            .unwrap()
+            .insert_file("a", ident("aaaa"))
+            .unwrap();
+        one.insert_file("b", ident("bbbb")).unwrap();
+        let mut two = Directory::default();
+        two.mkdir("sub").unwrap();
+        two.traverse_mut("sub", false)
+            .unwrap()
+            .insert_file("c", ident("cccc"))
+            .unwrap();
+        two.insert_file("b", ident("dddd")).unwrap();
+
+        let mut merged = one.clone();
+        assert!(matches!(
+            merged.merge(&two, MergePolicy::FailOnConflict),
+            Err(Error::FileEntryExistsAsFile(_))
+        ));
+
+        let mut merged = one.clone();
+        merged.merge(&two, MergePolicy::FirstWins).unwrap();
+        assert_eq!(merged.traverse("sub").unwrap().entries.len(), 2);
+        assert!(matches!(&merged.entries[OsStr::new("b")],
+                         DirectoryEntry::File(f) if f == &ident("bbbb")));
+
+        let mut merged = one;
+        merged.merge(&two, MergePolicy::LastWins).unwrap();
+        assert!(matches!(&merged.entries[OsStr::new("b")],
+                         DirectoryEntry::File(f) if f == &ident("dddd")));
+    }
+
+    #[test]
+    fn lookup_and_walk() {
+        let ident = |hash: &str| StorageIdentifier::new(hash.into(), 1, false);
+        let mut dir = Directory::default();
+        dir.insert_file("top", ident("aaaa")).unwrap();
+        dir.traverse_mut("sub/deeper", true)
+            .unwrap()
+            .insert_file("leaf", ident("bbbb"))
+            .unwrap();
+        dir.mkdir("empty").unwrap();
+        dir.insert_symlink("link", "top").unwrap();
+        dir.insert_symlink("link", "top").unwrap();
+        assert!(matches!(
+            dir.insert_symlink("link", "elsewhere"),
+            Err(Error::SymlinkEntryExistsAsSymlink(_))
+        ));
+        assert!(matches!(
+            dir.insert_file("link", ident("cccc")),
+            Err(Error::FileEntryExistsAsSymlink(_))
+        ));
+        assert!(matches!(
+            dir.mkdir("link"),
+            Err(Error::DirectoryEntryExistsAsSymlink(_))
+        ));
+
+        assert!(matches!(dir.get("top"), Some(DirectoryEntry::File(f)) if f.hash() == "aaaa"));
+        assert!(matches!(
+            dir.get("sub/deeper"),
+            Some(DirectoryEntry::Directory(_))
+        ));
+        assert!(dir.get("sub/missing").is_none());
+        assert!(dir.get("top/inner").is_none());
+        assert!(dir.get("").is_none());
+        assert!(matches!(dir.get("link"), Some(DirectoryEntry::Symlink(t)) if t == "top"));
+        assert_eq!(dir.iter().count(), 4);
+        assert!(matches!(dir.remove("link"), Ok(DirectoryEntry::Symlink(_))));
+        assert!(matches!(dir.remove("link"), Err(Error::EntryNotFound(_))));
+        assert!(matches!(dir.remove(""), Err(Error::EntryNotFound(_))));
+        assert_eq!(dir.iter().count(), 3);
+
+        let mut files: Vec<_> = dir.walk().collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("sub/deeper/leaf"), &ident("bbbb")),
+                (PathBuf::from("top"), &ident("aaaa")),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_directories() {
+        let ident = |hash: &str, executable| StorageIdentifier::new(hash.into(), 1, executable);
+        let mut old = Directory::default();
+        old.insert_file("same", ident("aaaa", false)).unwrap();
+        old.insert_file("content", ident("bbbb", false)).unwrap();
+        old.insert_file("mode", ident("cccc", false)).unwrap();
+        old.insert_file("gone", ident("dddd", false)).unwrap();
+        old.insert_symlink("link", "same").unwrap();
+        old.traverse_mut("became_file/inner", true)
+            .unwrap()
+            .insert_file("leaf", ident("eeee", false))
+            .unwrap();
+        old.traverse_mut("unchanged", true)
+            .unwrap()
+            .insert_file("leaf", ident("ffff", false))
+            .unwrap();
+        let mut new = old.clone();
+        for name in &["content", "mode", "gone", "link", "became_file"] {
+            new.entries.remove(OsStr::new(name));
+        }
+        new.insert_file("content", ident("1111", false)).unwrap();
+        new.insert_file("mode", ident("cccc", true)).unwrap();
+        new.insert_symlink("link", "content").unwrap();
+        new.insert_file("became_file", ident("2222", false))
+            .unwrap();
+        new.traverse_mut("added", true)
+            .unwrap()
+            .insert_file("leaf", ident("3333", false))
+            .unwrap();
+
+        assert_eq!(old.diff(&old).count(), 0);
+        let file = |hash, executable| ChangedEntry::File(ident(hash, executable));
+        let changes: Vec<_> = old.diff(&new).collect();
+        assert_eq!(
+            changes,
+            vec![
+                Change::Added {
+                    path: "added".into(),
+                    new: ChangedEntry::Directory,
+                },
+                Change::Added {
+                    path: "added/leaf".into(),
+                    new: file("3333", false),
+                },
+                Change::TypeChanged {
+                    path: "became_file".into(),
+                    old: ChangedEntry::Directory,
+                    new: file("2222", false),
+                },
+                Change::Removed {
+                    path: "became_file/inner".into(),
+                    old: ChangedEntry::Directory,
+                },
+                Change::Removed {
+                    path: "became_file/inner/leaf".into(),
+                    old: file("eeee", false),
+                },
+                Change::Modified {
+                    path: "content".into(),
+                    old: file("bbbb", false),
+                    new: file("1111", false),
+                },
+                Change::Removed {
+                    path: "gone".into(),
+                    old: file("dddd", false),
+                },
+                Change::Modified {
+                    path: "link".into(),
+                    old: ChangedEntry::Symlink("same".into()),
+                    new: ChangedEntry::Symlink("content".into()),
+                },
+                Change::Modified {
+                    path: "mode".into(),
+                    old: file("cccc", false),
+                    new: file("cccc", true),
+                },
+            ]
+        );
+        assert_eq!(changes[5].path(), Path::new("content"));
+        assert_eq!(
+            changes[5].new_entry().and_then(ChangedEntry::identity),
+            Some(&ident("1111", false))
+        );
+        assert!(changes[6].new_entry().is_none());
+        assert_eq!(old.diff(&new).count(), new.diff(&old).count());
+    }
 }
@@ -16,6 +16,10 @@ pub enum Error {
     EntryNotFound(OsString),
     #[error("entry {0:?} was not a directory when traversing storage index")]
     EntryNotDirectory(OsString),
+    #[error("entry {0:?} is a directory in storage index")]
+    EntryIsDirectory(PathBuf),
+    #[error("entry {0:?} is a symbolic link in storage index")]
+    EntryIsSymlink(PathBuf),
     #[error("unexpected prefix component encountered when traversing storage index for {0:?}")]
     UnexpectedPrefix(PathBuf),
     #[error(
@@ -31,7 +35,23 @@ pub enum Error {
     #[error("error parsing index")]
     ParsingIndex(json5::Error),
     #[error("serialising index")]
-    SerialisingIndex(json5::Error),
+    SerialisingIndex(bincode::Error),
+    #[error("error decoding index {0:?}: {1}")]
+    DecodingIndex(PathBuf, bincode::Error),
+    #[error("directory {0} not found in storage")]
+    MissingTree(String),
+    #[error("directory {0} in storage does not match its digest")]
+    CorruptTree(String),
+    #[error("invalid digest {0:?} of {1} bytes")]
+    InvalidDigest(String, i64),
+    #[error("invalid tree: {0}")]
+    InvalidTree(String),
+    #[error("name {0:?} is not valid UTF-8")]
+    NameNotUtf8(OsString),
+    #[error("content {0} not found in storage")]
+    BlobNotFound(String),
+    #[error("index {0:?} is in unsupported format version {1}")]
+    UnsupportedIndexVersion(PathBuf, u32),
     #[error("error while writing index file")]
     WritingIndex(PathBuf, std::io::Error),
     #[error("file data packet out of order when unpacking into storage")]
@@ -40,16 +60,64 @@ pub enum Error {
     FileEntryExistsAsDirectory(PathBuf),
     #[error("entry exists as different file when trying to insert {0:?}")]
     FileEntryExistsAsFile(PathBuf),
+    #[error("entry exists as symbolic link when trying to insert {0:?}")]
+    FileEntryExistsAsSymlink(PathBuf),
     #[error("entry exists as a file when trying to make directory {0:?}")]
     DirectoryEntryExistsAsFile(PathBuf),
+    #[error("entry exists as a symbolic link when trying to make directory {0:?}")]
+    DirectoryEntryExistsAsSymlink(PathBuf),
+    #[error("entry exists as directory when trying to insert symbolic link {0:?}")]
+    SymlinkEntryExistsAsDirectory(PathBuf),
+    #[error("entry exists as file when trying to insert symbolic link {0:?}")]
+    SymlinkEntryExistsAsFile(PathBuf),
+    #[error("entry exists as different symbolic link when trying to insert {0:?}")]
+    SymlinkEntryExistsAsSymlink(PathBuf),
     #[error("attempted to import a file too large for resource provider {0:?} is {1} bytes")]
     ImpossibleFileClaim(PathBuf, usize),
+    #[error("chunk of file data for {0:?} too large ({1} bytes) when unpacking into storage")]
+    FileChunkTooLarge(PathBuf, usize),
+    #[error("file {0:?} expected to be {1} bytes but was {2} bytes when unpacking into storage")]
+    FileSizeMismatch(PathBuf, u64, u64),
     #[error("unexpected end of content when unpacking into storage")]
     UnexpectedEndOfContent,
+    #[error("content of {0:?} does not match its hash {1}")]
+    ContentMismatch(PathBuf, String),
     #[error("expected file data event, got something else when unpacking into storage")]
     ExpectedFileDataEvent,
     #[error("IO error while adding entry {0:?} into storage: {1:?}")]
     IOErrorAddingToStorage(PathBuf, std::io::Error),
+    #[error("index {0:?} not found in storage")]
+    IndexNotFound(OsString),
+    #[error("index {0:?} already exists in storage")]
+    IndexExists(OsString),
+    #[error("error while removing index file {0:?}")]
+    RemovingIndex(PathBuf, std::io::Error),
+    #[error("IO error while collecting garbage at {0:?}: {1:?}")]
+    CollectingGarbage(PathBuf, std::io::Error),
+    #[error("IO error while compressing data file {0:?}: {1:?}")]
+    CompressingBlob(PathBuf, std::io::Error),
+    #[error("IO error while checking storage at {0:?}: {1:?}")]
+    CheckingStorage(PathBuf, std::io::Error),
+    #[error("IO error while repairing storage at {0:?}: {1:?}")]
+    Repairing(PathBuf, std::io::Error),
+    #[error("IO error while locking {0:?}: {1:?}")]
+    Locking(PathBuf, std::io::Error),
+    #[error("IO error while reading lease {0:?}: {1:?}")]
+    ReadingLease(PathBuf, std::io::Error),
+    #[error("IO error while updating lease {0:?}: {1:?}")]
+    UpdatingLease(PathBuf, std::io::Error),
+    #[error("serialising lease")]
+    SerialisingLease(bincode::Error),
+    #[error("IO error while tracking use of index {0:?}: {1:?}")]
+    TrackingUse(PathBuf, std::io::Error),
+    #[error("IO error while gathering statistics at {0:?}: {1:?}")]
+    GatheringStats(PathBuf, std::io::Error),
+    #[error("IO error while exporting {0:?}: {1:?}")]
+    Exporting(PathBuf, std::io::Error),
+    #[error("IO error while writing tarball of {0:?}: {1:?}")]
+    WritingTarball(PathBuf, std::io::Error),
+    #[error("IO error while reading {0:?} from storage: {1:?}")]
+    ReadingBlob(PathBuf, std::io::Error),
     #[error("the import stream raised an error: {0}")]
     ImportStreamError(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
@@ -0,0 +1,313 @@
+//! An HTTP server offering a storage as a Bazel remote cache
+//!
+//! Bazel's simple HTTP caching protocol stores content under `/cas/HASH`
+//! and action results under `/ac/HASH`, each retrieved with `GET` and
+//! stored with `PUT`.  Content is served straight from the data files of the
+//! storage, and content being stored must match the hash it is stored under.
+//! Action results are kept apart from the data files, in their own namespace
+//! of the storage, since they are named by the hash of the action rather than
+//! of their own content.
+//!
+//! As with the [gRPC server](crate::server), content stored through the cache
+//! is not referenced by any index, so is removed by garbage collection unless
+//! an index comes to refer to it.  Action results are left alone by garbage
+//! collection, but count towards the storage's quota, if it has one, and are
+//! evicted along with indices, least recently used first.
+
+use bytes::Bytes;
+use fehler::{throw, throws};
+use futures::{Stream, StreamExt};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::mpsc;
+
+use std::convert::Infallible;
+use std::path::Path;
+
+use crate::storage::{valid_hash, ContentReader, StorageIdentifier, EMPTY_HASH};
+use crate::{Error, SharedStorage};
+
+/// The largest action result which may be stored
+const MAX_ACTION_RESULT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The largest amount of content sent in a single piece of a response body
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Serve the content of a storage as a Bazel remote cache on incoming
+/// connections
+///
+/// The cache may be given any path prefix by clients, since only the last
+/// two components of a request's path are considered.  This only returns
+/// once the incoming connections are exhausted.
+pub async fn serve<I, IO, IE>(storage: SharedStorage, incoming: I) -> Result<(), hyper::Error>
+where
+    I: Stream<Item = Result<IO, IE>>,
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let make_service = make_service_fn(move |_| {
+        let storage = storage.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(storage.clone(), req))) }
+    });
+    Server::builder(accept::from_stream(incoming))
+        .serve(make_service)
+        .await
+}
+
+async fn handle(storage: SharedStorage, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(respond(storage, req).await.unwrap_or_else(|status| {
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap()
+    }))
+}
+
+#[throws(StatusCode)]
+async fn respond(storage: SharedStorage, req: Request<Body>) -> Response<Body> {
+    let mut parts = req.uri().path().rsplit('/');
+    let hash = parts.next().unwrap_or("").to_string();
+    let namespace = parts.next().unwrap_or("");
+    match (namespace, req.method()) {
+        ("cas", &Method::GET) => get_content(&storage, &hash, true).await?,
+        ("cas", &Method::HEAD) => get_content(&storage, &hash, false).await?,
+        ("cas", &Method::PUT) => put_content(&storage, &hash, req).await?,
+        ("ac", &Method::GET) => get_action(&storage, &hash, true).await?,
+        ("ac", &Method::HEAD) => get_action(&storage, &hash, false).await?,
+        ("ac", &Method::PUT) => put_action(&storage, &hash, req).await?,
+        ("cas", _) | ("ac", _) => throw!(StatusCode::METHOD_NOT_ALLOWED),
+        _ => throw!(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Report a storage error to the client
+fn status(error: Error) -> StatusCode {
+    match error {
+        Error::InvalidDigest(..)
+        | Error::FileSizeMismatch(..)
+        | Error::ContentMismatch(..)
+        | Error::UnexpectedEndOfContent => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn ok(size: usize, body: Body) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_LENGTH, size)
+        .body(body)
+        .unwrap()
+}
+
+#[throws(StatusCode)]
+fn content_length(req: &Request<Body>) -> u64 {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok())
+        .ok_or(StatusCode::LENGTH_REQUIRED)?
+}
+
+/// A body which streams content out of the storage
+fn content_body(content: ContentReader) -> Body {
+    Body::wrap_stream(futures::stream::unfold(Some(content), |content| async {
+        let mut content = content?;
+        let mut data = Vec::new();
+        match (&mut content)
+            .take(READ_CHUNK_SIZE)
+            .read_to_end(&mut data)
+            .await
+        {
+            Ok(0) => None,
+            Ok(_) => Some((Ok(Bytes::from(data)), Some(content))),
+            Err(e) => Some((Err(e), None)),
+        }
+    }))
+}
+
+#[throws(StatusCode)]
+async fn get_content(storage: &SharedStorage, hash: &str, with_body: bool) -> Response<Body> {
+    let identity = match storage.find_hash(hash).await.map_err(status)? {
+        Some(identity) => identity,
+        // The empty content is always present, as it is to REAPI clients
+        None if hash == EMPTY_HASH => return ok(0, Body::empty()),
+        None => throw!(StatusCode::NOT_FOUND),
+    };
+    if !with_body {
+        return ok(identity.size(), Body::empty());
+    }
+    let content = storage.open_blob(&identity).await.map_err(status)?;
+    ok(identity.size(), content_body(content))
+}
+
+#[throws(StatusCode)]
+async fn put_content(storage: &SharedStorage, hash: &str, req: Request<Body>) -> Response<Body> {
+    if !valid_hash(hash) {
+        throw!(StatusCode::BAD_REQUEST);
+    }
+    let size = content_length(&req)?;
+    let identity = StorageIdentifier::new(hash.to_string(), size as usize, false);
+    if (size == 0 && hash == EMPTY_HASH)
+        || storage
+            .find_blob(&identity)
+            .await
+            .map_err(status)?
+            .is_some()
+    {
+        // There is no need to store content which is already present
+        return ok(0, Body::empty());
+    }
+    let name = Path::new(req.uri().path()).to_owned();
+    let (mut chunks, receiver) = mpsc::channel(1);
+    let mut body = req.into_body();
+    let send = async move {
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    if chunks.send(Some(chunk)).await.is_err() {
+                        // The failure is reported by the spooling
+                        return;
+                    }
+                }
+                // Leaving the content unfinished abandons it
+                Err(_) => return,
+            }
+        }
+        let _ = chunks.send(None).await;
+    };
+    let (stored, ()) = futures::join!(storage.spool_blob(&name, &identity, receiver), send);
+    stored.map_err(status)?;
+    ok(0, Body::empty())
+}
+
+#[throws(StatusCode)]
+async fn get_action(storage: &SharedStorage, hash: &str, with_body: bool) -> Response<Body> {
+    match storage.read_action(hash).await.map_err(status)? {
+        Some(content) if with_body => ok(content.len(), content.into()),
+        Some(content) => ok(content.len(), Body::empty()),
+        None => throw!(StatusCode::NOT_FOUND),
+    }
+}
+
+#[throws(StatusCode)]
+async fn put_action(storage: &SharedStorage, hash: &str, req: Request<Body>) -> Response<Body> {
+    if content_length(&req)? > MAX_ACTION_RESULT_SIZE {
+        throw!(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let content = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    storage.write_action(hash, &content).await.map_err(status)?;
+    ok(0, Body::empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::ImportEvent;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    fn hash(data: &[u8]) -> String {
+        use sha2::{Digest, Sha256};
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn loopback() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let events = vec![
+            ImportEvent::File(None, "tool".into(), 4, true),
+            ImportEvent::FileData(Bytes::from_static(b"tool")),
+        ];
+        ss.import("one", &mut provider, futures::stream::iter(events))
+            .await
+            .unwrap();
+
+        let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            serve(ss, listener.incoming()).await.unwrap();
+        });
+        let client = hyper::Client::new();
+        let request = |method: Method, path: String, body: Body| {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://{}{}", addr, path))
+                .body(body)
+                .unwrap();
+            client.request(req)
+        };
+        let body = |response: Response<Body>| hyper::body::to_bytes(response.into_body());
+
+        // Content imported as executable is still found by its hash
+        let path = format!("/cas/{}", hash(b"tool"));
+        let response = request(Method::GET, path.clone(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await.unwrap(), "tool");
+        let response = request(Method::HEAD, path.clone(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "4");
+        let response = request(Method::DELETE, path, Body::empty()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let big: Vec<u8> = (0..200_000u32).map(|n| n as u8).collect();
+        let path = format!("/prefix/cas/{}", hash(&big));
+        let response = request(Method::GET, path.clone(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = request(Method::PUT, path.clone(), big.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request(Method::GET, path, Body::empty()).await.unwrap();
+        assert_eq!(body(response).await.unwrap(), big);
+
+        // The empty content is always present
+        let path = format!("/cas/{}", hash(b""));
+        let response = request(Method::GET, path.clone(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await.unwrap(), "");
+        let response = request(Method::HEAD, path, Body::empty()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "0");
+
+        // Content which doesn't match its hash is not stored
+        let path = format!("/cas/{}", hash(b"right"));
+        let response = request(Method::PUT, path.clone(), "wrong".into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = request(Method::GET, path, Body::empty()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let path = format!("/ac/{}", hash(b"action"));
+        let response = request(Method::GET, path.clone(), Body::empty())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = request(Method::PUT, path.clone(), "result".into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request(Method::GET, path, Body::empty()).await.unwrap();
+        assert_eq!(body(response).await.unwrap(), "result");
+        let response = request(Method::PUT, "/ac/nonsense".into(), "result".into())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
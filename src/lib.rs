@@ -13,12 +13,22 @@
 //! references will not be removed from the storage.  Removing an index from
 //! the shared storage model may result in space being freed up.
 //!
+//! As in the RemoteExecution API, every directory has a digest computed from
+//! its content, and directories are stored by their digest.  Indices which
+//! share identical subtrees therefore only store those subtrees once, and
+//! comparing two trees is simply a matter of comparing their digests.
+//!
 //! Shared storages are populated by importing tarballs to create indices.  Indices
-//! can be merged to form new indices, and storages are depopulated by removing
-//! indices.
+//! can be merged to form new indices, or edited to derive new indices from them,
+//! and storages are depopulated by removing indices, or by evicting the least
+//! recently used indices to keep within a quota.
 //!
 //! Shared storage is meant to be used in an asynchronous situation and so uses
 //! tokio for all its filesystem accesses.
+//!
+//! Several processes may use the same shared storage at once.  They coordinate
+//! with advisory locks on files under the base directory, and each sees the
+//! indices created and removed by the others when it refreshes its storage.
 
 mod error;
 pub use error::Error;
@@ -27,8 +37,16 @@ pub use error::Error;
 mod traits;
 pub use traits::{ResourceAllocation, ResourceClaimResult, ResourceProvider};
 
+mod chunker;
+pub use chunker::ChunkingConfig;
+
+mod lock;
+
 pub mod entry;
+pub mod http_cache;
+pub mod reapi;
+pub mod server;
 pub mod storage;
-pub use storage::SharedStorage;
+pub use storage::{QuotaConfig, SharedStorage, StorageConfig};
 
 pub mod util;
@@ -0,0 +1,145 @@
+//! Advisory locks on files, so that several processes can share a storage
+//!
+//! Locks are taken with `flock(2)`, and so belong to the open file rather
+//! than to the process.  Two storages opened on the same base directory in
+//! one process therefore exclude one another just as two processes do.
+//! A lock is released when it is dropped.
+
+use fehler::{throw, throws};
+use tokio::io;
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Wait for a shared lock on a file, creating the file if need be
+    #[throws(io::Error)]
+    pub(crate) async fn shared(path: &Path) -> Self {
+        Self::acquire(path, false).await?
+    }
+
+    /// Wait for an exclusive lock on a file, creating the file if need be
+    #[throws(io::Error)]
+    pub(crate) async fn exclusive(path: &Path) -> Self {
+        Self::acquire(path, true).await?
+    }
+
+    /// Take an exclusive lock on a file if nothing else holds a lock on it,
+    /// creating the file if need be
+    #[throws(io::Error)]
+    pub(crate) fn try_exclusive(path: &Path) -> Option<Self> {
+        let file = Self::open(path)?;
+        match lock(&file, true, false)? {
+            true => Some(Self { _file: file }),
+            false => None,
+        }
+    }
+
+    #[throws(io::Error)]
+    async fn acquire(path: &Path, exclusive: bool) -> Self {
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || Self::acquire_blocking(&path, exclusive))
+            .await
+            .map_err(io::Error::from)??
+    }
+
+    #[throws(io::Error)]
+    fn acquire_blocking(path: &Path, exclusive: bool) -> Self {
+        let file = Self::open(path)?;
+        lock(&file, exclusive, true)?;
+        Self { _file: file }
+    }
+
+    #[throws(io::Error)]
+    fn open(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?
+    }
+}
+
+/// Lock a file, returning whether it was locked, which is always the case
+/// when waiting for the lock
+#[cfg(unix)]
+#[throws(io::Error)]
+fn lock(file: &File, exclusive: bool, wait: bool) -> bool {
+    use std::os::unix::io::AsRawFd;
+    let mut operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if !wait {
+        operation |= libc::LOCK_NB;
+    }
+    // Safety: the file descriptor is valid for the duration of the call
+    while unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::Interrupted => {}
+            io::ErrorKind::WouldBlock => return false,
+            _ => throw!(e),
+        }
+    }
+    true
+}
+#[cfg(not(unix))]
+#[throws(io::Error)]
+fn lock(_file: &File, _exclusive: bool, _wait: bool) -> bool {
+    throw!(io::Error::new(
+        io::ErrorKind::Other,
+        "file locking is not supported on this platform"
+    ));
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn exclusion() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let path = td.path().join("lock");
+        let first = FileLock::acquire_blocking(&path, false).unwrap();
+        let second = FileLock::acquire_blocking(&path, false).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let waiter = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let lock = FileLock::acquire_blocking(&path, true).unwrap();
+                sender.send(()).unwrap();
+                lock
+            })
+        };
+        drop(first);
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(second);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        let exclusive = waiter.join().unwrap();
+        assert!(FileLock::try_exclusive(&path).unwrap().is_none());
+
+        let (sender, receiver) = mpsc::channel();
+        let waiter = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let _lock = FileLock::acquire_blocking(&path, false).unwrap();
+                sender.send(()).unwrap();
+            })
+        };
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+        drop(exclusive);
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        waiter.join().unwrap();
+        assert!(FileLock::try_exclusive(&path).unwrap().is_some());
+    }
+}
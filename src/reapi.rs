@@ -0,0 +1,429 @@
+//! Conversion to and from the Remote Execution API
+//!
+//! This contains the subset of the `build.bazel.remote.execution.v2`
+//! protobuf messages needed to describe content and trees, along with
+//! conversions between them and the storage's own [`StorageIdentifier`] and
+//! [`Directory`](crate::entry::Directory).
+//!
+//! REAPI has no notion of a file's content being executable, only of a file
+//! node being executable, so a [`Digest`] identifies content whatever its
+//! executable bit.  Names in REAPI must be valid UTF-8.
+//!
+//! The messages for the `ContentAddressableStorage` service are also here,
+//! for use with the [server](crate::server).
+
+use fehler::{throw, throws};
+use prost::Message;
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+
+use crate::entry::{self, DirectoryEntry};
+use crate::storage::{valid_hash, StorageIdentifier};
+use crate::Error;
+
+/// A content digest, the SHA-256 of the content and its size
+#[derive(Clone, PartialEq, Eq, Hash, Message)]
+pub struct Digest {
+    #[prost(string, tag = "1")]
+    pub hash: String,
+    #[prost(int64, tag = "2")]
+    pub size_bytes: i64,
+}
+
+/// A file in a [`Directory`]
+#[derive(Clone, PartialEq, Message)]
+pub struct FileNode {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, optional, tag = "2")]
+    pub digest: Option<Digest>,
+    #[prost(bool, tag = "4")]
+    pub is_executable: bool,
+}
+
+/// A subdirectory in a [`Directory`], referred to by the digest of its
+/// encoded [`Directory`] message
+#[derive(Clone, PartialEq, Message)]
+pub struct DirectoryNode {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, optional, tag = "2")]
+    pub digest: Option<Digest>,
+}
+
+/// A symbolic link in a [`Directory`]
+#[derive(Clone, PartialEq, Message)]
+pub struct SymlinkNode {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub target: String,
+}
+
+/// A single directory, with each kind of entry sorted by name
+#[derive(Clone, PartialEq, Message)]
+pub struct Directory {
+    #[prost(message, repeated, tag = "1")]
+    pub files: Vec<FileNode>,
+    #[prost(message, repeated, tag = "2")]
+    pub directories: Vec<DirectoryNode>,
+    #[prost(message, repeated, tag = "3")]
+    pub symlinks: Vec<SymlinkNode>,
+}
+
+/// A directory along with every directory beneath it
+#[derive(Clone, PartialEq, Message)]
+pub struct Tree {
+    #[prost(message, optional, tag = "1")]
+    pub root: Option<Directory>,
+    #[prost(message, repeated, tag = "2")]
+    pub children: Vec<Directory>,
+}
+
+/// The `google.rpc.Status` of an individual request in a batch
+#[derive(Clone, PartialEq, Message)]
+pub struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    pub code: i32,
+    #[prost(string, tag = "2")]
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FindMissingBlobsRequest {
+    #[prost(string, tag = "1")]
+    pub instance_name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub blob_digests: Vec<Digest>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FindMissingBlobsResponse {
+    #[prost(message, repeated, tag = "2")]
+    pub missing_blob_digests: Vec<Digest>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BatchUpdateBlobsRequest {
+    #[prost(string, tag = "1")]
+    pub instance_name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub requests: Vec<batch_update_blobs_request::Request>,
+}
+
+pub mod batch_update_blobs_request {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Request {
+        #[prost(message, optional, tag = "1")]
+        pub digest: Option<Digest>,
+        #[prost(bytes, tag = "2")]
+        pub data: Vec<u8>,
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BatchUpdateBlobsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub responses: Vec<batch_update_blobs_response::Response>,
+}
+
+pub mod batch_update_blobs_response {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Response {
+        #[prost(message, optional, tag = "1")]
+        pub digest: Option<Digest>,
+        #[prost(message, optional, tag = "2")]
+        pub status: Option<RpcStatus>,
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BatchReadBlobsRequest {
+    #[prost(string, tag = "1")]
+    pub instance_name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub digests: Vec<Digest>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BatchReadBlobsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub responses: Vec<batch_read_blobs_response::Response>,
+}
+
+pub mod batch_read_blobs_response {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Response {
+        #[prost(message, optional, tag = "1")]
+        pub digest: Option<Digest>,
+        #[prost(bytes, tag = "2")]
+        pub data: Vec<u8>,
+        #[prost(message, optional, tag = "3")]
+        pub status: Option<RpcStatus>,
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetTreeRequest {
+    #[prost(string, tag = "1")]
+    pub instance_name: String,
+    #[prost(message, optional, tag = "2")]
+    pub root_digest: Option<Digest>,
+    #[prost(int32, tag = "3")]
+    pub page_size: i32,
+    #[prost(string, tag = "4")]
+    pub page_token: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetTreeResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub directories: Vec<Directory>,
+    #[prost(string, tag = "2")]
+    pub next_page_token: String,
+}
+
+impl From<&StorageIdentifier> for Digest {
+    fn from(identity: &StorageIdentifier) -> Self {
+        Self {
+            hash: identity.hash().to_owned(),
+            size_bytes: identity.size() as i64,
+        }
+    }
+}
+
+impl StorageIdentifier {
+    /// The identity of the content with the given digest
+    #[throws(Error)]
+    pub fn from_digest(digest: &Digest, executable: bool) -> Self {
+        if !valid_hash(&digest.hash) || digest.size_bytes < 0 {
+            throw!(Error::InvalidDigest(digest.hash.clone(), digest.size_bytes));
+        }
+        Self::new(digest.hash.clone(), digest.size_bytes as usize, executable)
+    }
+}
+
+impl Directory {
+    /// The digest of this directory, by which other directories refer to it
+    pub fn digest(&self) -> Digest {
+        use sha2::{Digest as _, Sha256};
+        let mut encoded = Vec::with_capacity(self.encoded_len());
+        // Encoding into a Vec can only fail if it runs out of capacity
+        self.encode(&mut encoded).unwrap();
+        Digest {
+            hash: format!("{:x}", Sha256::digest(&encoded)),
+            size_bytes: encoded.len() as i64,
+        }
+    }
+}
+
+impl Tree {
+    /// Describe a directory, and everything beneath it, as a tree
+    ///
+    /// Subdirectories which are identical are only included once.
+    #[throws(Error)]
+    pub fn from_directory(dir: &entry::Directory) -> Self {
+        let mut children = HashMap::new();
+        let root = convert_directory(dir, &mut children)?;
+        let mut children: Vec<_> = children.into_iter().collect();
+        children.sort_by(|a, b| a.0.hash.cmp(&b.0.hash));
+        Self {
+            root: Some(root),
+            children: children.into_iter().map(|(_, child)| child).collect(),
+        }
+    }
+
+    /// Reassemble the directory described by this tree
+    #[throws(Error)]
+    pub fn to_directory(&self) -> entry::Directory {
+        let children: HashMap<_, _> = self
+            .children
+            .iter()
+            .map(|child| (child.digest(), child))
+            .collect();
+        let mut dir = entry::Directory::default();
+        if let Some(root) = &self.root {
+            fill_directory(&mut dir, root, &children)?;
+        }
+        dir
+    }
+}
+
+/// Convert a directory, adding the conversions of any subdirectories to the
+/// children
+#[throws(Error)]
+fn convert_directory(
+    dir: &entry::Directory,
+    children: &mut HashMap<Digest, Directory>,
+) -> Directory {
+    let mut ret = Directory::default();
+    for (name, entry) in dir.iter() {
+        let name = utf8(name)?;
+        match entry {
+            DirectoryEntry::Directory(d) => {
+                let child = convert_directory(d, children)?;
+                let digest = child.digest();
+                children.entry(digest.clone()).or_insert(child);
+                ret.directories.push(DirectoryNode {
+                    name,
+                    digest: Some(digest),
+                });
+            }
+            DirectoryEntry::File(identity) => ret.files.push(FileNode {
+                name,
+                digest: Some(identity.into()),
+                is_executable: identity.executable(),
+            }),
+            DirectoryEntry::Symlink(target) => ret.symlinks.push(SymlinkNode {
+                name,
+                target: utf8(target)?,
+            }),
+        }
+    }
+    ret.files.sort_by(|a, b| a.name.cmp(&b.name));
+    ret.directories.sort_by(|a, b| a.name.cmp(&b.name));
+    ret.symlinks.sort_by(|a, b| a.name.cmp(&b.name));
+    ret
+}
+
+/// Fill in a directory from its description, looking up subdirectories in
+/// the children by their digests
+#[throws(Error)]
+fn fill_directory(
+    dir: &mut entry::Directory,
+    node: &Directory,
+    children: &HashMap<Digest, &Directory>,
+) {
+    let missing_digest = |name: &str| Error::InvalidTree(format!("{:?} has no digest", name));
+    for file in &node.files {
+        let digest = file
+            .digest
+            .as_ref()
+            .ok_or_else(|| missing_digest(&file.name))?;
+        let identity = StorageIdentifier::from_digest(digest, file.is_executable)?;
+        dir.insert_file(valid_name(&file.name)?, identity)?;
+    }
+    for symlink in &node.symlinks {
+        dir.insert_symlink(valid_name(&symlink.name)?, &symlink.target)?;
+    }
+    for subdir in &node.directories {
+        let name = valid_name(&subdir.name)?;
+        let digest = subdir
+            .digest
+            .as_ref()
+            .ok_or_else(|| missing_digest(&subdir.name))?;
+        let child = children
+            .get(digest)
+            .ok_or_else(|| Error::MissingTree(digest.hash.clone()))?;
+        dir.mkdir(name)?;
+        fill_directory(dir.traverse_mut(name, false)?, child, children)?;
+    }
+}
+
+/// Names must be a single path component
+#[throws(Error)]
+fn valid_name(name: &str) -> &str {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        throw!(Error::InvalidTree(format!("invalid name {:?}", name)));
+    }
+    name
+}
+
+#[throws(Error)]
+fn utf8(name: &OsStr) -> String {
+    name.to_str()
+        .ok_or_else(|| Error::NameNotUtf8(OsString::from(name)))?
+        .to_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ident(hash: char, size: usize, executable: bool) -> StorageIdentifier {
+        StorageIdentifier::new(hash.to_string().repeat(64), size, executable)
+    }
+
+    #[test]
+    fn tree_roundtrip() {
+        let mut dir = entry::Directory::default();
+        dir.insert_file("tool", ident('a', 1, true)).unwrap();
+        dir.insert_symlink("link", "tool").unwrap();
+        for sub in &["one/same", "two/same"] {
+            dir.traverse_mut(sub, true)
+                .unwrap()
+                .insert_file("file", ident('b', 2, false))
+                .unwrap();
+        }
+        dir.mkdir("empty").unwrap();
+
+        let tree = Tree::from_directory(&dir).unwrap();
+        let root = tree.root.as_ref().unwrap();
+        assert_eq!(root.files.len(), 1);
+        assert!(root.files[0].is_executable);
+        let names: Vec<_> = root.directories.iter().map(|d| &d.name[..]).collect();
+        assert_eq!(names, vec!["empty", "one", "two"]);
+        // empty, the shared same, and one and two which are also identical
+        assert_eq!(tree.children.len(), 3);
+        assert_eq!(
+            Directory::default().digest().hash,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let mut encoded = Vec::new();
+        tree.encode(&mut encoded).unwrap();
+        let decoded = Tree::decode(&encoded[..]).unwrap();
+        assert_eq!(decoded.to_directory().unwrap(), dir);
+    }
+
+    #[test]
+    fn bad_trees() {
+        let file = |name: &str, hash: &str| FileNode {
+            name: name.into(),
+            digest: Some(Digest {
+                hash: hash.into(),
+                size_bytes: 1,
+            }),
+            is_executable: false,
+        };
+        let tree = |files| Tree {
+            root: Some(Directory {
+                files,
+                ..Default::default()
+            }),
+            children: Vec::new(),
+        };
+        let good = "a".repeat(64);
+        assert!(tree(vec![file("ok", &good)]).to_directory().is_ok());
+        assert!(matches!(
+            tree(vec![file("..", &good)]).to_directory(),
+            Err(Error::InvalidTree(_))
+        ));
+        assert!(matches!(
+            tree(vec![file("a/b", &good)]).to_directory(),
+            Err(Error::InvalidTree(_))
+        ));
+        assert!(matches!(
+            tree(vec![file("ok", "AAAA")]).to_directory(),
+            Err(Error::InvalidDigest(_, _))
+        ));
+        let missing = Tree {
+            root: Some(Directory {
+                directories: vec![DirectoryNode {
+                    name: "sub".into(),
+                    digest: Some(Directory::default().digest()),
+                }],
+                ..Default::default()
+            }),
+            children: Vec::new(),
+        };
+        assert!(matches!(missing.to_directory(), Err(Error::MissingTree(_))));
+    }
+}
@@ -0,0 +1,884 @@
+//! A gRPC server offering the content of a storage to REAPI clients
+//!
+//! This implements the Remote Execution API's `ContentAddressableStorage`
+//! service, along with the `google.bytestream.ByteStream` service which
+//! REAPI clients use to transfer content too large for a batch request.
+//! Content is read from and written to the same data files as are used by
+//! indices.  Content uploaded by a client is not referenced by any index, so
+//! will be removed by garbage collection unless an index comes to refer to it.
+//!
+//! Instance names are accepted but ignored; every instance is the storage.
+
+use fehler::{throw, throws};
+use prost::Message;
+use sha2::Sha256;
+use tokio::io::{self, AsyncReadExt};
+use tokio::sync::mpsc;
+use tonic::body::BoxBody;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::codegen::{http, BoxFuture, Context, Future, HttpBody, Never, Poll, Service, StdError};
+use tonic::server::Grpc;
+use tonic::transport::server::Connected;
+use tonic::transport::NamedService;
+use tonic::{Code, Request, Response, Status};
+
+use futures::{Stream, TryFutureExt};
+
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+
+use crate::reapi::*;
+use crate::storage::{ContentReader, StorageIdentifier, EMPTY_HASH};
+use crate::{Error, SharedStorage};
+
+const CAS: &str = "build.bazel.remote.execution.v2.ContentAddressableStorage";
+const BYTESTREAM: &str = "google.bytestream.ByteStream";
+
+/// The largest amount of content sent in a single ByteStream read response
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadRequest {
+    #[prost(string, tag = "1")]
+    pub resource_name: String,
+    #[prost(int64, tag = "2")]
+    pub read_offset: i64,
+    #[prost(int64, tag = "3")]
+    pub read_limit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadResponse {
+    #[prost(bytes, tag = "10")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(string, tag = "1")]
+    pub resource_name: String,
+    #[prost(int64, tag = "2")]
+    pub write_offset: i64,
+    #[prost(bool, tag = "3")]
+    pub finish_write: bool,
+    #[prost(bytes, tag = "10")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteResponse {
+    #[prost(int64, tag = "1")]
+    pub committed_size: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryWriteStatusRequest {
+    #[prost(string, tag = "1")]
+    pub resource_name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryWriteStatusResponse {
+    #[prost(int64, tag = "1")]
+    pub committed_size: i64,
+    #[prost(bool, tag = "2")]
+    pub complete: bool,
+}
+
+/// The `ContentAddressableStorage` service
+#[derive(Clone)]
+pub struct CasServer {
+    storage: SharedStorage,
+}
+
+impl CasServer {
+    pub fn new(storage: SharedStorage) -> Self {
+        Self { storage }
+    }
+}
+
+impl NamedService for CasServer {
+    const NAME: &'static str = CAS;
+}
+
+impl<B> Service<http::Request<B>> for CasServer
+where
+    B: HttpBody + Send + Sync + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Never;
+    type Future = BoxFuture<Self::Response, Never>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Never>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let storage = self.storage.clone();
+        match method(&req) {
+            "FindMissingBlobs" => unary(req, move |r| find_missing_blobs(storage.clone(), r)),
+            "BatchUpdateBlobs" => unary(req, move |r| batch_update_blobs(storage.clone(), r)),
+            "BatchReadBlobs" => unary(req, move |r| batch_read_blobs(storage.clone(), r)),
+            "GetTree" => server_streaming(req, move |r| get_tree(storage.clone(), r)),
+            _ => unimplemented(),
+        }
+    }
+}
+
+/// The `google.bytestream.ByteStream` service
+#[derive(Clone)]
+pub struct ByteStreamServer {
+    storage: SharedStorage,
+}
+
+impl ByteStreamServer {
+    pub fn new(storage: SharedStorage) -> Self {
+        Self { storage }
+    }
+}
+
+impl NamedService for ByteStreamServer {
+    const NAME: &'static str = BYTESTREAM;
+}
+
+impl<B> Service<http::Request<B>> for ByteStreamServer
+where
+    B: HttpBody + Send + Sync + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Never;
+    type Future = BoxFuture<Self::Response, Never>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Never>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let storage = self.storage.clone();
+        match method(&req) {
+            "Read" => server_streaming(req, move |r| read(storage.clone(), r)),
+            "Write" => client_streaming(req, move |r| write(storage.clone(), r)),
+            "QueryWriteStatus" => unary(req, move |r| query_write_status(storage.clone(), r)),
+            _ => unimplemented(),
+        }
+    }
+}
+
+/// Serve the content of a storage to REAPI clients on incoming connections
+///
+/// This only returns once the incoming connections are exhausted.
+pub async fn serve<I, IO, IE>(
+    storage: SharedStorage,
+    incoming: I,
+) -> Result<(), tonic::transport::Error>
+where
+    I: Stream<Item = Result<IO, IE>>,
+    IO: io::AsyncRead + io::AsyncWrite + Connected + Unpin + Send + 'static,
+    IE: Into<StdError>,
+{
+    tonic::transport::Server::builder()
+        .add_service(CasServer::new(storage.clone()))
+        .add_service(ByteStreamServer::new(storage))
+        .serve_with_incoming(incoming)
+        .await
+}
+
+/// Adapts a function handling a method's request into a service
+struct Method<F>(F);
+
+impl<F, Fut, Req, Resp> Service<Request<Req>> for Method<F>
+where
+    F: FnMut(Req) -> Fut,
+    Fut: Future<Output = Result<Resp, Status>>,
+{
+    type Response = Response<Resp>;
+    type Error = Status;
+    type Future = futures::future::MapOk<Fut, fn(Resp) -> Response<Resp>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Req>) -> Self::Future {
+        (self.0)(request.into_inner()).map_ok(Response::new)
+    }
+}
+
+type ResponseStream<T> = mpsc::Receiver<Result<T, Status>>;
+
+fn method<B>(req: &http::Request<B>) -> &str {
+    req.uri().path().rsplit('/').next().unwrap_or("")
+}
+
+fn unary<B, F, Fut, Req, Resp>(
+    req: http::Request<B>,
+    handler: F,
+) -> BoxFuture<http::Response<BoxBody>, Never>
+where
+    B: HttpBody + Send + Sync + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    F: FnMut(Req) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+    Req: Message + Default + Send + 'static,
+    Resp: Message + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::default());
+        Ok(grpc.unary(Method(handler), req).await)
+    })
+}
+
+fn server_streaming<B, F, Fut, Req, Resp>(
+    req: http::Request<B>,
+    handler: F,
+) -> BoxFuture<http::Response<BoxBody>, Never>
+where
+    B: HttpBody + Send + Sync + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    F: FnMut(Req) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<ResponseStream<Resp>, Status>> + Send + 'static,
+    Req: Message + Default + Send + 'static,
+    Resp: Message + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::default());
+        Ok(grpc.server_streaming(Method(handler), req).await)
+    })
+}
+
+fn client_streaming<B, F, Fut, Req, Resp>(
+    req: http::Request<B>,
+    handler: F,
+) -> BoxFuture<http::Response<BoxBody>, Never>
+where
+    B: HttpBody + Send + Sync + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    F: FnMut(Streaming<Req>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Resp, Status>> + Send + 'static,
+    Req: Message + Default + Send + 'static,
+    Resp: Message + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::default());
+        Ok(grpc.client_streaming(Method(handler), req).await)
+    })
+}
+
+fn unimplemented() -> BoxFuture<http::Response<BoxBody>, Never> {
+    Box::pin(async move {
+        Ok(http::Response::builder()
+            .status(200)
+            .header("grpc-status", (Code::Unimplemented as i32).to_string())
+            .header("content-type", "application/grpc")
+            .body(BoxBody::empty())
+            .unwrap())
+    })
+}
+
+/// Report a storage error to the client
+fn status(error: Error) -> Status {
+    match error {
+        Error::InvalidDigest(..)
+        | Error::FileSizeMismatch(..)
+        | Error::ContentMismatch(..)
+        | Error::UnexpectedEndOfContent => Status::invalid_argument(error.to_string()),
+        Error::BlobNotFound(_) => Status::not_found(error.to_string()),
+        _ => Status::internal(error.to_string()),
+    }
+}
+
+fn rpc_status(result: Result<(), Status>) -> RpcStatus {
+    match result {
+        Ok(()) => RpcStatus::default(),
+        Err(status) => RpcStatus {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+        },
+    }
+}
+
+// tonic's `Status` is too large to return in a `Result` from anything but
+// the handlers, so helpers return what the handlers need to build one
+
+fn identity(digest: &Digest) -> Result<StorageIdentifier, Error> {
+    StorageIdentifier::from_digest(digest, false)
+}
+
+fn missing_digest() -> Status {
+    Status::invalid_argument("digest is missing")
+}
+
+/// Find the digest named by a ByteStream resource name
+///
+/// Reads name `{instance_name}/blobs/{hash}/{size}` and writes name
+/// `{instance_name}/uploads/{uuid}/blobs/{hash}/{size}`, either of which
+/// may be followed by further metadata.
+fn resource(name: &str) -> Option<Digest> {
+    let parts: Vec<_> = name.split('/').collect();
+    parts.windows(3).filter(|w| w[0] == "blobs").find_map(|w| {
+        Some(Digest {
+            hash: w[1].to_string(),
+            size_bytes: w[2].parse().ok()?,
+        })
+    })
+}
+
+fn invalid_resource(name: &str) -> Status {
+    Status::invalid_argument(format!("invalid resource name {:?}", name))
+}
+
+/// Find content in the storage, which may be the empty content
+#[throws(Status)]
+async fn find(storage: &SharedStorage, identity: &StorageIdentifier) -> Option<StorageIdentifier> {
+    if identity.size() == 0 && identity.hash() == EMPTY_HASH {
+        return Some(identity.clone());
+    }
+    storage.find_blob(identity).await.map_err(status)?
+}
+
+#[throws(Status)]
+async fn open(storage: &SharedStorage, identity: &StorageIdentifier) -> ContentReader {
+    match find(storage, identity).await? {
+        Some(_) if identity.size() == 0 => Box::new(io::empty()),
+        Some(found) => storage.open_blob(&found).await.map_err(status)?,
+        None => throw!(status(Error::BlobNotFound(identity.hash().to_string()))),
+    }
+}
+
+#[throws(Status)]
+async fn read_blob(storage: &SharedStorage, digest: &Digest) -> Vec<u8> {
+    let identity = identity(digest).map_err(status)?;
+    let mut content = open(storage, &identity).await?;
+    let mut data = Vec::with_capacity(identity.size());
+    content
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+    data
+}
+
+#[throws(Status)]
+async fn update_blob(storage: &SharedStorage, digest: &Digest, data: &[u8]) {
+    use sha2::Digest;
+    let identity = identity(digest).map_err(status)?;
+    let hash = format!("{:x}", Sha256::digest(data));
+    if data.len() != identity.size() || hash != identity.hash() {
+        throw!(Status::invalid_argument(format!(
+            "content does not match digest {}/{}",
+            digest.hash, digest.size_bytes
+        )));
+    }
+    storage.insert_blob(&identity, data).await.map_err(status)?;
+}
+
+#[throws(Status)]
+async fn find_missing_blobs(
+    storage: SharedStorage,
+    request: FindMissingBlobsRequest,
+) -> FindMissingBlobsResponse {
+    let mut missing_blob_digests = Vec::new();
+    for digest in request.blob_digests {
+        let identity = identity(&digest).map_err(status)?;
+        if find(&storage, &identity).await?.is_none() {
+            missing_blob_digests.push(digest);
+        }
+    }
+    FindMissingBlobsResponse {
+        missing_blob_digests,
+    }
+}
+
+#[throws(Status)]
+async fn batch_update_blobs(
+    storage: SharedStorage,
+    request: BatchUpdateBlobsRequest,
+) -> BatchUpdateBlobsResponse {
+    let mut responses = Vec::new();
+    for request in request.requests {
+        let result = match &request.digest {
+            Some(digest) => update_blob(&storage, digest, &request.data).await,
+            None => Err(missing_digest()),
+        };
+        responses.push(batch_update_blobs_response::Response {
+            digest: request.digest,
+            status: Some(rpc_status(result)),
+        });
+    }
+    BatchUpdateBlobsResponse { responses }
+}
+
+#[throws(Status)]
+async fn batch_read_blobs(
+    storage: SharedStorage,
+    request: BatchReadBlobsRequest,
+) -> BatchReadBlobsResponse {
+    let mut responses = Vec::new();
+    for digest in request.digests {
+        let (data, result) = match read_blob(&storage, &digest).await {
+            Ok(data) => (data, Ok(())),
+            Err(e) => (Vec::new(), Err(e)),
+        };
+        responses.push(batch_read_blobs_response::Response {
+            digest: Some(digest),
+            data,
+            status: Some(rpc_status(result)),
+        });
+    }
+    BatchReadBlobsResponse { responses }
+}
+
+/// Send the directories of a tree, breadth first, a page at a time
+///
+/// Directories missing from the storage are left out, along with their
+/// content, as REAPI requires, unless the root itself is missing.  A page
+/// token is the number of directories sent before the page it asks for, so
+/// the tree is walked afresh for every page, and pages are sent as the walk
+/// fills them.
+#[throws(Status)]
+async fn get_tree(
+    storage: SharedStorage,
+    request: GetTreeRequest,
+) -> ResponseStream<GetTreeResponse> {
+    let root = request.root_digest.ok_or_else(missing_digest)?;
+    let skip = match request.page_token.as_str() {
+        "" => 0,
+        token => token
+            .parse::<usize>()
+            .map_err(|_| Status::invalid_argument(format!("invalid page token {:?}", token)))?,
+    };
+    let page_size = match request.page_size {
+        size if size > 0 => size as usize,
+        _ => usize::MAX,
+    };
+    let root_directory = read_directory(&storage, &root).await?;
+    let (mut sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let walked: Result<(), Status> = async {
+            let mut seen: HashSet<_> = vec![root].into_iter().collect();
+            let mut queue = VecDeque::new();
+            let mut directory = root_directory;
+            let mut page = Vec::new();
+            let mut offset = 0;
+            'walk: loop {
+                queue.extend(
+                    directory
+                        .directories
+                        .iter()
+                        .filter_map(|d| d.digest.clone())
+                        .filter(|digest| seen.insert(digest.clone())),
+                );
+                if offset >= skip {
+                    if page.len() == page_size {
+                        let full = GetTreeResponse {
+                            directories: std::mem::take(&mut page),
+                            next_page_token: offset.to_string(),
+                        };
+                        if sender.send(Ok(full)).await.is_err() {
+                            // The client has gone away
+                            return Ok(());
+                        }
+                    }
+                    page.push(directory);
+                }
+                offset += 1;
+                directory = loop {
+                    let digest = match queue.pop_front() {
+                        Some(digest) => digest,
+                        None => break 'walk,
+                    };
+                    match read_directory(&storage, &digest).await {
+                        Ok(directory) => break directory,
+                        Err(e) if e.code() == Code::NotFound => {}
+                        Err(e) => return Err(e),
+                    }
+                };
+            }
+            let last = GetTreeResponse {
+                directories: page,
+                next_page_token: String::new(),
+            };
+            let _ = sender.send(Ok(last)).await;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = walked {
+            let _ = sender.send(Err(e)).await;
+        }
+    });
+    receiver
+}
+
+#[throws(Status)]
+async fn read_directory(storage: &SharedStorage, digest: &Digest) -> Directory {
+    let data = read_blob(storage, digest).await?;
+    Directory::decode(&data[..])
+        .map_err(|_| Status::invalid_argument(format!("{} is not a directory", digest.hash)))?
+}
+
+#[throws(Status)]
+async fn read(storage: SharedStorage, request: ReadRequest) -> ResponseStream<ReadResponse> {
+    let digest =
+        resource(&request.resource_name).ok_or_else(|| invalid_resource(&request.resource_name))?;
+    let identity = identity(&digest).map_err(status)?;
+    let size = identity.size() as i64;
+    if request.read_offset < 0 || request.read_offset > size || request.read_limit < 0 {
+        throw!(Status::out_of_range(format!(
+            "cannot read {} bytes from offset {} of {}",
+            request.read_limit, request.read_offset, request.resource_name
+        )));
+    }
+    let mut content = open(&storage, &identity).await?;
+    let offset = request.read_offset as u64;
+    let mut remaining = match request.read_limit {
+        0 => u64::MAX,
+        limit => limit as u64,
+    };
+    let (mut sender, receiver) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let sent: Result<(), io::Error> = async {
+            io::copy(&mut (&mut content).take(offset), &mut io::sink()).await?;
+            while remaining > 0 {
+                let mut data = Vec::new();
+                (&mut content)
+                    .take(remaining.min(READ_CHUNK_SIZE))
+                    .read_to_end(&mut data)
+                    .await?;
+                if data.is_empty() {
+                    break;
+                }
+                remaining -= data.len() as u64;
+                if sender.send(Ok(ReadResponse { data })).await.is_err() {
+                    // The client has gone away
+                    break;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = sent {
+            let _ = sender.send(Err(Status::internal(e.to_string()))).await;
+        }
+    });
+    receiver
+}
+
+#[throws(Status)]
+async fn write(storage: SharedStorage, mut requests: Streaming<WriteRequest>) -> WriteResponse {
+    let first = match requests.message().await? {
+        Some(first) => first,
+        None => throw!(Status::invalid_argument("no data written")),
+    };
+    let digest =
+        resource(&first.resource_name).ok_or_else(|| invalid_resource(&first.resource_name))?;
+    let identity = identity(&digest).map_err(status)?;
+    let committed_size = identity.size() as i64;
+    if find(&storage, &identity).await?.is_some() {
+        // There is no need to upload content which is already present
+        return WriteResponse { committed_size };
+    }
+    let (mut chunks, receiver) = mpsc::channel(1);
+    let spool = {
+        let storage = storage.clone();
+        let name = PathBuf::from(&first.resource_name);
+        let identity = identity.clone();
+        tokio::spawn(async move { storage.spool_blob(&name, &identity, receiver).await })
+    };
+    let mut written = 0;
+    let mut request = Some(first);
+    while let Some(current) = request.take() {
+        if current.write_offset != written {
+            throw!(Status::invalid_argument(format!(
+                "write at offset {} of {} bytes written",
+                current.write_offset, written
+            )));
+        }
+        written += current.data.len() as i64;
+        // Should spooling fail, that is reported once it is complete
+        let _ = chunks.send(Some(current.data.into())).await;
+        if current.finish_write {
+            let _ = chunks.send(None).await;
+            break;
+        }
+        request = requests.message().await?;
+    }
+    drop(chunks);
+    spool
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(status)?;
+    WriteResponse {
+        committed_size: written,
+    }
+}
+
+#[throws(Status)]
+async fn query_write_status(
+    storage: SharedStorage,
+    request: QueryWriteStatusRequest,
+) -> QueryWriteStatusResponse {
+    let digest =
+        resource(&request.resource_name).ok_or_else(|| invalid_resource(&request.resource_name))?;
+    let identity = identity(&digest).map_err(status)?;
+    if find(&storage, &identity).await?.is_none() {
+        // Interrupted uploads are abandoned rather than resumed
+        throw!(Status::not_found(format!(
+            "{} has not been written",
+            request.resource_name
+        )));
+    }
+    QueryWriteStatusResponse {
+        committed_size: identity.size() as i64,
+        complete: true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::ImportEvent;
+    use bytes::Bytes;
+    use futures::stream;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::transport::Channel;
+
+    type Client = tonic::client::Grpc<Channel>;
+
+    fn digest(data: &[u8]) -> Digest {
+        use sha2::Digest;
+        super::Digest {
+            hash: format!("{:x}", Sha256::digest(data)),
+            size_bytes: data.len() as i64,
+        }
+    }
+
+    async fn call<Req, Resp>(client: &mut Client, path: &'static str, request: Req) -> Resp
+    where
+        Req: Message + Send + Sync + 'static,
+        Resp: Message + Default + Send + Sync + 'static,
+    {
+        client.ready().await.unwrap();
+        let path = PathAndQuery::from_static(path);
+        client
+            .unary(Request::new(request), path, ProstCodec::default())
+            .await
+            .unwrap()
+            .into_inner()
+    }
+
+    async fn call_streaming<Req, Resp>(
+        client: &mut Client,
+        path: &'static str,
+        request: Req,
+    ) -> Vec<Resp>
+    where
+        Req: Message + Send + Sync + 'static,
+        Resp: Message + Default + Send + Sync + 'static,
+    {
+        client.ready().await.unwrap();
+        let path = PathAndQuery::from_static(path);
+        let mut responses = client
+            .server_streaming(Request::new(request), path, ProstCodec::default())
+            .await
+            .unwrap()
+            .into_inner();
+        let mut result = Vec::new();
+        while let Some(response) = responses.message().await.unwrap() {
+            result.push(response);
+        }
+        result
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn loopback() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let events = vec![
+            ImportEvent::Directory("bin".into()),
+            ImportEvent::File(Some("bin".into()), "tool".into(), 4, true),
+            ImportEvent::FileData(Bytes::from_static(b"tool")),
+        ];
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let tree = ss.reapi_tree("one").unwrap();
+
+        let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            serve(ss, listener.incoming()).await.unwrap();
+        });
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = Client::new(channel);
+
+        let absent = digest(b"absent");
+        let response: FindMissingBlobsResponse = call(
+            &mut client,
+            "/build.bazel.remote.execution.v2.ContentAddressableStorage/FindMissingBlobs",
+            FindMissingBlobsRequest {
+                instance_name: String::new(),
+                blob_digests: vec![digest(b"tool"), absent.clone(), digest(b"")],
+            },
+        )
+        .await;
+        assert_eq!(response.missing_blob_digests, vec![absent.clone()]);
+
+        // Upload the directories of the tree, along with something bad
+        let mut requests: Vec<_> = std::iter::once(tree.root.clone().unwrap())
+            .chain(tree.children.iter().cloned())
+            .map(|dir| {
+                let mut data = Vec::new();
+                dir.encode(&mut data).unwrap();
+                batch_update_blobs_request::Request {
+                    digest: Some(digest(&data)),
+                    data,
+                }
+            })
+            .collect();
+        requests.push(batch_update_blobs_request::Request {
+            digest: Some(absent.clone()),
+            data: b"wrong".to_vec(),
+        });
+        let response: BatchUpdateBlobsResponse = call(
+            &mut client,
+            "/build.bazel.remote.execution.v2.ContentAddressableStorage/BatchUpdateBlobs",
+            BatchUpdateBlobsRequest {
+                instance_name: String::new(),
+                requests,
+            },
+        )
+        .await;
+        let codes: Vec<_> = response
+            .responses
+            .iter()
+            .map(|r| r.status.as_ref().unwrap().code)
+            .collect();
+        assert_eq!(codes, vec![0, 0, Code::InvalidArgument as i32]);
+
+        let response: BatchReadBlobsResponse = call(
+            &mut client,
+            "/build.bazel.remote.execution.v2.ContentAddressableStorage/BatchReadBlobs",
+            BatchReadBlobsRequest {
+                instance_name: String::new(),
+                digests: vec![digest(b"tool"), absent.clone()],
+            },
+        )
+        .await;
+        assert_eq!(response.responses[0].data, b"tool");
+        assert_eq!(response.responses[0].status.as_ref().unwrap().code, 0);
+        assert_eq!(
+            response.responses[1].status.as_ref().unwrap().code,
+            Code::NotFound as i32
+        );
+
+        let root = tree.root.as_ref().unwrap();
+        let pages: Vec<GetTreeResponse> = call_streaming(
+            &mut client,
+            "/build.bazel.remote.execution.v2.ContentAddressableStorage/GetTree",
+            GetTreeRequest {
+                instance_name: String::new(),
+                root_digest: Some(root.digest()),
+                page_size: 1,
+                page_token: String::new(),
+            },
+        )
+        .await;
+        assert_eq!(pages.len(), 2);
+        assert_eq!(&pages[0].directories[0], root);
+        assert_eq!(pages[1].directories, tree.children);
+        assert!(pages[1].next_page_token.is_empty());
+
+        let pages: Vec<GetTreeResponse> = call_streaming(
+            &mut client,
+            "/build.bazel.remote.execution.v2.ContentAddressableStorage/GetTree",
+            GetTreeRequest {
+                instance_name: String::new(),
+                root_digest: Some(root.digest()),
+                page_size: 0,
+                page_token: pages[0].next_page_token.clone(),
+            },
+        )
+        .await;
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].directories, tree.children);
+
+        // Write something large enough to need several pieces
+        let big: Vec<u8> = (0..200_000u32).map(|n| n as u8).collect();
+        let big_digest = digest(&big);
+        let name = format!(
+            "instance/uploads/some-uuid/blobs/{}/{}",
+            big_digest.hash, big_digest.size_bytes
+        );
+        let writes: Vec<_> = big
+            .chunks(70_000)
+            .enumerate()
+            .map(|(n, data)| WriteRequest {
+                resource_name: name.clone(),
+                write_offset: n as i64 * 70_000,
+                finish_write: (n + 1) * 70_000 >= big.len(),
+                data: data.to_vec(),
+            })
+            .collect();
+        client.ready().await.unwrap();
+        let response: WriteResponse = client
+            .client_streaming(
+                Request::new(stream::iter(writes)),
+                PathAndQuery::from_static("/google.bytestream.ByteStream/Write"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.committed_size, big.len() as i64);
+
+        let response: QueryWriteStatusResponse = call(
+            &mut client,
+            "/google.bytestream.ByteStream/QueryWriteStatus",
+            QueryWriteStatusRequest {
+                resource_name: name,
+            },
+        )
+        .await;
+        assert!(response.complete);
+
+        let name = format!("blobs/{}/{}", big_digest.hash, big_digest.size_bytes);
+        let reads: Vec<ReadResponse> = call_streaming(
+            &mut client,
+            "/google.bytestream.ByteStream/Read",
+            ReadRequest {
+                resource_name: name.clone(),
+                read_offset: 0,
+                read_limit: 0,
+            },
+        )
+        .await;
+        assert!(reads.len() > 1);
+        let read: Vec<u8> = reads.into_iter().flat_map(|r| r.data).collect();
+        assert_eq!(read, big);
+
+        let reads: Vec<ReadResponse> = call_streaming(
+            &mut client,
+            "/google.bytestream.ByteStream/Read",
+            ReadRequest {
+                resource_name: name,
+                read_offset: 1000,
+                read_limit: 10,
+            },
+        )
+        .await;
+        let read: Vec<u8> = reads.into_iter().flat_map(|r| r.data).collect();
+        assert_eq!(read, &big[1000..1010]);
+    }
+}
@@ -7,46 +7,395 @@ use futures::stream::FuturesUnordered;
 use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
-use tokio::io::{self, AsyncWriteExt};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 
-use std::collections::HashMap;
+use async_compression::tokio_02::bufread::{ZstdDecoder, ZstdEncoder};
+use async_compression::Level;
+use bincode::Options;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
-use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::Poll;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::entry::*;
+use crate::lock::FileLock;
+use crate::reapi;
+use crate::util::{TarImportStream, TarWriter};
 use crate::Error;
-use crate::{ResourceAllocation, ResourceClaimResult, ResourceProvider};
+use crate::{ChunkingConfig, ResourceAllocation, ResourceClaimResult, ResourceProvider};
 
 const DATA: &str = "data";
+const CHUNKS: &str = "chunks";
+const COMPRESSED: &str = "zst";
 const INDICES: &str = "indices";
-const MAX_METADATA_SIZE: u64 = 1 * 1024 * 1024;
+const QUARANTINE: &str = "quarantine";
+const TREES: &str = "trees";
+const ACTIONS: &str = "ac";
+const LOCK: &str = "lock";
+const LOCKS: &str = "locks";
+const LEASES: &str = "leases";
+const USED: &str = "used";
+/// Legacy json5 indices are read entirely into memory, so are limited in size
+const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+/// Binary indices start with this, followed by the format version
+const INDEX_MAGIC: &[u8; 8] = b"SSINDEX\0";
+/// Version 1 binary indices contain the entire tree of the index
+const FLAT_INDEX_VERSION: u32 = 1;
+/// Version 2 binary indices contain only the digest of the root directory,
+/// with the directories themselves stored under the trees directory
+const TREE_INDEX_VERSION: u32 = 2;
+
+/// The largest chunk of file data permitted when importing a chunked file
+pub const IMPORT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Used to give unique names to files being spooled into the storage
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Futures which are inserting file content into the storage during an import
+type Inserters<'a> =
+    FuturesUnordered<BoxFuture<'a, Result<(Option<PathBuf>, OsString, StorageIdentifier), Error>>>;
 
 struct InMemoryIndex {
     dir: Directory,
-    dirty: bool,
+    /// The digest of the root directory of the index
+    digest: String,
+    /// Whether the index was loaded from an index file in an older format
+    legacy: bool,
 }
 
 impl From<Directory> for InMemoryIndex {
     fn from(dir: Directory) -> Self {
-        Self { dir, dirty: false }
+        Self {
+            digest: dir.digest(),
+            dir,
+            legacy: false,
+        }
+    }
+}
+
+/// Statistics kept between calls to `stats`, so that they are only
+/// gathered again for what has changed
+///
+/// The counts of data files are kept up to date as data is added and removed,
+/// so the data only needs scanning again after a refresh.  Two imports which
+/// race to add the same data may count it twice until then.
+#[derive(Default)]
+struct StatsCache {
+    /// Counts changes to the data files, so that counts of them which were
+    /// under way meanwhile are not kept
+    generation: u64,
+    /// How many data files there are, and their size, not counting
+    /// temporary files
+    data: Option<(usize, u64)>,
+    /// The content referred to by the root directories of indices, by digest
+    content: HashMap<String, Arc<TreeContent>>,
+    /// The statistics for each index, along with its digest at the time
+    indices: Option<(BTreeMap<OsString, String>, BTreeMap<OsString, IndexStats>)>,
+}
+
+impl StatsCache {
+    /// Forget the counts of data files, which may have changed in ways not
+    /// known to this process
+    fn data_changed(&mut self) {
+        self.generation += 1;
+        self.data = None;
+    }
+
+    /// Count a data file of some size which has been added to the storage
+    fn data_added(&mut self, bytes: u64) {
+        self.generation += 1;
+        if let Some((blobs, total)) = &mut self.data {
+            *blobs += 1;
+            *total += bytes;
+        }
+    }
+
+    /// Count a data file of some size which has been removed from the storage
+    fn data_removed(&mut self, bytes: u64) {
+        self.generation += 1;
+        if let Some((blobs, total)) = &mut self.data {
+            *blobs = blobs.saturating_sub(1);
+            *total = total.saturating_sub(bytes);
+        }
     }
 }
 
+/// The content a directory refers to
+struct TreeContent {
+    /// The size of every file, counting duplicates
+    logical_bytes: u64,
+    identities: HashSet<StorageIdentifier>,
+}
+
+/// A handle on a shared storage
+///
+/// Handles are cheap to clone, and every clone refers to the same storage, so
+/// one storage can be used from many tasks at once.  Imports and reads all
+/// proceed in parallel, since the indices are only locked for long enough to
+/// look an index up or to replace it.  An index which has been looked up
+/// stays usable even if it is replaced or removed meanwhile.
+#[derive(Clone)]
 pub struct SharedStorage {
     base: PathBuf,
-    indices: HashMap<OsString, InMemoryIndex>,
+    config: StorageConfig,
+    indices: Arc<RwLock<HashMap<OsString, Arc<InMemoryIndex>>>>,
+    stats: Arc<Mutex<StatsCache>>,
+}
+
+/// Configuration for how a shared storage stores its data
+///
+/// The configuration only affects how new data is written into the storage
+/// and how much is kept, data already in the storage can always be read
+/// whatever the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfig {
+    /// If set, files larger than the maximum chunk size are split into
+    /// content defined chunks which are stored individually, so that
+    /// similar large files can share the storage for their common content.
+    pub chunking: Option<ChunkingConfig>,
+    /// If set, data is stored compressed with zstd at this level.  Data is
+    /// still identified by the hash of its uncompressed content.
+    pub compression: Option<u32>,
+    /// If set, indices containing a directory which is larger than this many
+    /// bytes when stored can neither be saved nor loaded.  Legacy json5
+    /// indices are always limited to 1 MiB.
+    pub max_index_size: Option<u64>,
+    /// If set, whenever an index is created the least recently used indices
+    /// and action results are evicted as needed to keep the data in the
+    /// storage within the quota, see [`SharedStorage::enforce_quota`].
+    pub quota: Option<QuotaConfig>,
+}
+
+/// A limit on the amount of data kept in a storage
+///
+/// Action results count towards the quota along with the data files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaConfig {
+    /// Indices are evicted once there are more than this many bytes of data
+    pub limit: u64,
+    /// Indices are evicted until there are no more than this many bytes of
+    /// data, or until nothing more can be evicted
+    pub low_water: u64,
+}
+
+/// The outcome of compressing the existing data in a storage
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CompressionReport {
+    /// How many raw data files were replaced by compressed ones
+    pub blobs_compressed: usize,
+    /// How many bytes the raw data files took up
+    pub bytes_before: u64,
+    /// How many bytes the compressed data files take up
+    pub bytes_after: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct StorageIdentifier {
     hash: String,
     size: usize,
     executable: bool,
 }
 
+/// The outcome of a garbage collection run over the storage
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GarbageReport {
+    /// How many data files were removed from the storage
+    pub blobs_freed: usize,
+    /// How many bytes of data were removed from the storage
+    pub bytes_freed: u64,
+    /// How many stored directories were removed from the storage
+    pub trees_freed: usize,
+    /// How many expired leases were removed from the storage
+    pub leases_expired: usize,
+    /// Lease files which could not be read, or which name directories no
+    /// longer in the storage.  These are left alone, but protect only what
+    /// could be found of their content.
+    pub broken_leases: Vec<PathBuf>,
+}
+
+/// How much a storage holds, and how much of it each index accounts for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    /// How many data files there are, counting chunks and chunk lists
+    pub blobs: usize,
+    /// How many bytes of data there are
+    pub bytes: u64,
+    /// How much of the content each index accounts for, by index name
+    pub indices: BTreeMap<OsString, IndexStats>,
+}
+
+/// How much content an index refers to
+///
+/// Sizes are of the content as it was imported, which is not the size of
+/// the data stored for it if it is compressed or shares chunks with other
+/// content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IndexStats {
+    /// The size of every file in the index, counting duplicates
+    pub logical_bytes: u64,
+    /// The size of the distinct content which only this index refers to
+    pub unique_bytes: u64,
+    /// The size of the distinct content which other indices refer to too
+    pub shared_bytes: u64,
+}
+
+/// The outcome of enforcing the quota on a storage
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct EvictionReport {
+    /// The indices removed from the storage, least recently used first
+    pub indices_evicted: Vec<OsString>,
+    /// How many action results were removed from the storage
+    pub actions_evicted: usize,
+    /// The garbage collected from the storage along the way
+    pub garbage: GarbageReport,
+    /// How many bytes of data and action results are left in the storage,
+    /// not counting temporary files
+    pub bytes_used: u64,
+}
+
+/// What a lease protects from garbage collection
+#[derive(Debug, Clone)]
+pub enum LeaseTarget {
+    /// The content of an index as it is when the lease is taken, even if the
+    /// index is removed before the lease expires
+    Index(OsString),
+    /// Particular data, which need not be in the storage yet
+    Identifiers(Vec<StorageIdentifier>),
+}
+
+/// A lease protecting content from garbage collection until it expires or
+/// is released
+///
+/// Leases are kept in the storage, so they outlive the process which took
+/// them, and can be found again with [`SharedStorage::leases`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    id: String,
+    expires: SystemTime,
+}
+
+impl Lease {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn expires(&self) -> SystemTime {
+        self.expires
+    }
+}
+
+/// A lease as it is stored
+#[derive(Serialize, Deserialize)]
+struct LeaseRecord {
+    expires: SystemTime,
+    /// The digests of directories protected along with their content
+    trees: Vec<String>,
+    blobs: Vec<StorageIdentifier>,
+}
+
+/// Something which can be evicted to keep a storage within its quota
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Victim {
+    Index(OsString),
+    /// An action cache entry, and its size
+    Action(PathBuf, u64),
+}
+
+/// What garbage collection must keep: the content of every index, and of
+/// every lease which has yet to expire
+struct Roots {
+    indices: HashMap<OsString, Arc<InMemoryIndex>>,
+    /// The directories protected by leases
+    trees: Vec<Directory>,
+    /// The data protected by leases apart from any directory
+    blobs: Vec<StorageIdentifier>,
+    /// Leases which have expired, and so protect nothing
+    expired: Vec<Lease>,
+    /// Lease files which could not be read, or which name missing trees
+    broken_leases: Vec<PathBuf>,
+}
+
+impl Roots {
+    /// Every directory whose content is kept
+    fn dirs(&self) -> impl Iterator<Item = &Directory> {
+        self.indices.values().map(|ime| &ime.dir).chain(&self.trees)
+    }
+
+    /// Every identity which is kept, not counting the chunks of data which
+    /// is stored as a list of chunks
+    fn identities(&self) -> impl Iterator<Item = &StorageIdentifier> {
+        self.dirs()
+            .flat_map(|dir| dir.walk())
+            .map(|(_, identity)| identity)
+            .chain(&self.blobs)
+    }
+}
+
+/// The problems found by checking the integrity of the storage
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Data referenced by an index, or by a chunk list, which is not present
+    /// in the storage in any form
+    pub missing: Vec<StorageIdentifier>,
+    /// Data files whose content does not match the hash and size in their
+    /// name.  When repairing, these are moved into the quarantine directory.
+    pub corrupt: Vec<PathBuf>,
+    /// Data files which are not referenced by any index
+    pub orphans: Vec<PathBuf>,
+    /// Temporary files left behind, possibly by an import in progress
+    pub stray_temporaries: Vec<PathBuf>,
+    /// Index files which could not be parsed
+    pub bad_indices: Vec<PathBuf>,
+    /// Lease files which could not be read, or which name directories no
+    /// longer in the storage
+    pub broken_leases: Vec<PathBuf>,
+    /// When repairing, data which was missing or corrupt and has been
+    /// repopulated from the supplied content
+    pub repaired: Vec<StorageIdentifier>,
+}
+
+impl FsckReport {
+    /// Whether or not the check found any problems with the storage
+    ///
+    /// Orphaned data is not considered a problem, since it is simply
+    /// garbage which has not been collected yet.
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty()
+            && self.corrupt.is_empty()
+            && self.stray_temporaries.is_empty()
+            && self.bad_indices.is_empty()
+            && self.broken_leases.is_empty()
+    }
+}
+
+/// A reader over the content of a file in the storage
+pub type ContentReader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// How file content is placed onto the filesystem when exporting an index
+///
+/// Content which is not stored as a single raw file, such as chunked
+/// content, is always copied whatever the mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportMode {
+    /// Copy the content out of the storage
+    Copy,
+    /// Hard link to the content in the storage.  Data files are read-only,
+    /// so the exported files are too, and they must not be made writable
+    /// since modifying them would corrupt the storage.
+    Hardlink,
+    /// Clone the content from the storage using a copy-on-write reflink.
+    /// This is only supported on Linux, and only on filesystems which
+    /// support the FICLONE ioctl, and must be on the same filesystem as the
+    /// storage.
+    Reflink,
+}
+
 /// Events yielded to the import process by whatever import stream
 /// is generating them.
 pub enum ImportEvent {
@@ -58,15 +407,69 @@ pub enum ImportEvent {
     /// the file.  Finally the boolean is true if the file needs to be marked
     /// as executable.  The file's data must not be loaded for this event.
     File(Option<PathBuf>, OsString, usize, bool),
-    /// The data for the previous File event.  The file's data is not loaded into
+    /// A symbolic link which needs to be created in the index.
+    /// As with files, the pathbuf is the path inside which the link should be
+    /// placed if present.  The link's name is next, and then its target.
+    Symlink(Option<PathBuf>, OsString, OsString),
+    /// A hard link which needs to be created in the index, as a file with the
+    /// same content as the file at the path which follows, which must be
+    /// created earlier in the import.  As with files, the pathbuf is the path
+    /// inside which the link should be placed if present, and the link's name
+    /// is next.
+    HardLink(Option<PathBuf>, OsString, PathBuf),
+    /// A file which needs to be created in the index, whose data will follow
+    /// in a number of FileData events, each no larger than IMPORT_CHUNK_SIZE,
+    /// terminated by an EndOfFile event.  The fields are as for File, except
+    /// that the size may be larger than fits in memory.  Only a chunk's worth
+    /// of resources are claimed to import such a file.
+    ChunkedFile(Option<PathBuf>, OsString, u64, bool),
+    /// The data for the previous File event, or a chunk of the data for the
+    /// previous ChunkedFile event.  The file's data is not loaded into
     /// memory until this event is drawn from the stream.
     FileData(Bytes),
+    /// The end of the data for the previous ChunkedFile event.
+    EndOfFile,
     /// An error of some kind has occurred in the stream and import should be
     /// aborted.
     Error(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 
+/// An edit to make when deriving a new index from an existing one, see
+/// [`SharedStorage::derive`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexEdit {
+    /// Remove the entry at a path, along with anything beneath it
+    Delete(PathBuf),
+    /// Create a directory, along with any missing parent directories
+    Mkdir(PathBuf),
+    /// Set whether the file at a path is executable
+    SetExecutable(PathBuf, bool),
+}
+
 impl StorageIdentifier {
+    pub(crate) fn new(hash: String, size: usize, executable: bool) -> Self {
+        Self {
+            hash,
+            size,
+            executable,
+        }
+    }
+
+    /// The SHA-256 of the content, in lower case hex
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// The size of the content in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether or not the content is considered executable
+    pub fn executable(&self) -> bool {
+        self.executable
+    }
+
     fn filename(&self, base: &Path) -> PathBuf {
         // Our structure is done as XX/YY/.......
         // In theory that means the dirs contain at most 256 entries at the
@@ -90,35 +493,185 @@ impl StorageIdentifier {
 impl SharedStorage {
     #[throws(Error)]
     pub async fn new<P: AsRef<Path>>(base: P) -> Self {
-        let mut ret = Self {
+        Self::new_with_config(base, StorageConfig::default()).await?
+    }
+
+    #[throws(Error)]
+    pub async fn new_with_config<P: AsRef<Path>>(base: P, config: StorageConfig) -> Self {
+        let ret = Self {
             base: base.as_ref().to_owned(),
-            indices: HashMap::new(),
+            config,
+            indices: Arc::new(RwLock::new(HashMap::new())),
+            stats: Arc::new(Mutex::new(StatsCache::default())),
         };
         ret.prepare_paths().await?;
-        ret.load_indices().await?;
+        ret.refresh().await?;
         ret
     }
 
+    /// Bring the in-memory indices up to date with the index files
+    ///
+    /// Unless the caller holds the storage lock exclusively, each index is
+    /// locked while it is read so that it cannot be removed, and its
+    /// directories collected as garbage, part way through.
     #[throws(Error)]
-    async fn load_indices(&mut self) {
+    async fn reload_indices(&self, lock: bool) -> usize {
+        let known = self.indices.read().unwrap().clone();
+        let mut on_disk = HashSet::new();
         let mut indexfiles = fs::read_dir(self.base.join(INDICES))
             .await
             .map_err(Error::Preparing)?;
-        let mut try_remove = Vec::new();
         while let Some(entry) = indexfiles.next_entry().await.map_err(Error::Preparing)? {
             let meta = entry.metadata().await.map_err(Error::Preparing)?;
-            if meta.is_file() {
-                if meta.len() > MAX_METADATA_SIZE {
-                    throw!(Error::IndexTooLarge(entry.path(), meta.len()))
+            // Temporary files are indices which are still being written
+            if meta.is_file() && entry.path().extension() != Some(OsStr::new("tmp")) {
+                on_disk.insert(entry.file_name());
+            }
+        }
+        let mut changes: Vec<_> = known
+            .keys()
+            .filter(|name| !on_disk.contains(*name))
+            .map(|name| (name.clone(), None))
+            .collect();
+        for name in on_disk {
+            let _lock = match lock {
+                true => Some(self.lock_index(&name, false).await?),
+                false => None,
+            };
+            let index_path = self.base.join(INDICES).join(&name);
+            let root = match read_index_root(&index_path).await {
+                Ok(root) => root,
+                // The index was removed since the directory was read
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    changes.push((name, None));
+                    continue;
                 }
-                let body = fs::read_to_string(&entry.path())
-                    .await
-                    .map_err(Error::Preparing)?;
-                let dir = Directory::try_from(body.as_ref()).map_err(Error::ParsingIndex)?;
-                self.indices.insert(entry.file_name(), dir.into());
-            } else {
-                try_remove.push(entry.path());
+                Err(e) => throw!(Error::Preparing(e)),
+            };
+            let unchanged = match (known.get(&name), root) {
+                (Some(ime), Some(root)) => ime.digest == root,
+                // Indices are only ever written in the current format
+                (Some(ime), None) => ime.legacy,
+                (None, _) => false,
+            };
+            if unchanged {
+                continue;
+            }
+            let (dir, legacy) =
+                read_index(&self.base, &index_path, self.config.max_index_size).await?;
+            let mut ime: InMemoryIndex = dir.into();
+            ime.legacy = legacy;
+            changes.push((name, Some(Arc::new(ime))));
+        }
+
+        // Indices changed by this process meanwhile are already up to date
+        let mut indices = self.indices.write().unwrap();
+        let mut changed = 0;
+        for (name, ime) in changes {
+            let untouched = match (indices.get(&name), known.get(&name)) {
+                (Some(current), Some(old)) => Arc::ptr_eq(current, old),
+                (current, old) => current.is_none() && old.is_none(),
+            };
+            if !untouched {
+                continue;
             }
+            match ime {
+                Some(ime) => indices.insert(name, ime),
+                None => indices.remove(&name),
+            };
+            changed += 1;
+        }
+        changed
+    }
+
+    /// Look up an index
+    #[throws(Error)]
+    fn index(&self, name: &OsStr) -> Arc<InMemoryIndex> {
+        let indices = self.indices.read().unwrap();
+        match indices.get(name) {
+            Some(ime) => ime.clone(),
+            None => throw!(Error::IndexNotFound(name.into())),
+        }
+    }
+
+    /// Make an index which has been saved available
+    fn publish(&self, name: &OsStr, ime: InMemoryIndex) {
+        let mut indices = self.indices.write().unwrap();
+        indices.insert(name.to_owned(), Arc::new(ime));
+    }
+
+    /// Lock the storage as a whole
+    ///
+    /// Anything which adds data or directories to the storage holds a shared
+    /// lock until they are referenced by an index, so that they cannot be
+    /// collected as garbage by another process, which holds an exclusive lock.
+    #[throws(Error)]
+    async fn lock_storage(&self, exclusive: bool) -> FileLock {
+        let path = self.base.join(LOCK);
+        let lock = match exclusive {
+            true => FileLock::exclusive(&path).await,
+            false => FileLock::shared(&path).await,
+        };
+        lock.map_err(|e| Error::Locking(path, e))?
+    }
+
+    /// Lock an index, exclusively to create, replace or remove it, otherwise
+    /// shared to read it
+    #[throws(Error)]
+    async fn lock_index(&self, name: &OsStr, exclusive: bool) -> FileLock {
+        let path = self.base.join(LOCKS).join(name);
+        let lock = match exclusive {
+            true => FileLock::exclusive(&path).await,
+            false => FileLock::shared(&path).await,
+        };
+        lock.map_err(|e| Error::Locking(path, e))?
+    }
+
+    /// Lock an index exclusively, unless it is already locked by anything
+    #[throws(Error)]
+    fn try_lock_index(&self, name: &OsStr) -> Option<FileLock> {
+        let path = self.base.join(LOCKS).join(name);
+        FileLock::try_exclusive(&path).map_err(|e| Error::Locking(path, e))?
+    }
+
+    /// Note that an index has just been used, so that it is not evicted
+    /// ahead of indices which have been used less recently
+    ///
+    /// The time of last use is kept as the modification time of a file named
+    /// for the index, so that every process sharing the storage sees it.
+    #[throws(Error)]
+    async fn record_use(&self, name: &OsStr) {
+        let path = self.base.join(USED).join(name);
+        mark_used(&path, true)
+            .await
+            .map_err(|e| Error::TrackingUse(path, e))?
+    }
+
+    /// Delete an index which the caller holds the exclusive lock on
+    #[throws(Error)]
+    async fn delete_index(&self, name: &OsStr) {
+        let index_path = self.base.join(INDICES).join(name);
+        fs::remove_file(&index_path)
+            .await
+            .map_err(|e| Error::RemovingIndex(index_path, e))?;
+        self.indices.write().unwrap().remove(name);
+        let used_path = self.base.join(USED).join(name);
+        match fs::remove_file(&used_path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => throw!(Error::TrackingUse(used_path, e)),
+        }
+    }
+
+    /// Fail if an index exists, even if only another process knows of it
+    #[throws(Error)]
+    async fn check_absent(&self, name: &OsStr) {
+        if self.indices.read().unwrap().contains_key(name)
+            || fs::metadata(self.base.join(INDICES).join(name))
+                .await
+                .is_ok()
+        {
+            throw!(Error::IndexExists(name.into()));
         }
     }
 
@@ -133,50 +686,72 @@ impl SharedStorage {
         fs::create_dir_all(self.base.join(INDICES))
             .await
             .map_err(Error::Preparing)?;
+        fs::create_dir_all(self.base.join(TREES))
+            .await
+            .map_err(Error::Preparing)?;
+        fs::create_dir_all(self.base.join(LOCKS))
+            .await
+            .map_err(Error::Preparing)?;
+        fs::create_dir_all(self.base.join(LEASES))
+            .await
+            .map_err(Error::Preparing)?;
+        fs::create_dir_all(self.base.join(USED))
+            .await
+            .map_err(Error::Preparing)?;
+        fs::create_dir_all(self.base.join(ACTIONS))
+            .await
+            .map_err(Error::Preparing)?;
     }
 
     #[throws(Error)]
-    async fn save_index(&mut self, name: &OsStr) {
-        let ime = self.indices.get_mut(name).unwrap_or_else(|| {
-            panic!("Internal error: Attempted to save unknown index {:?}", name)
+    async fn save_index(&self, name: &OsStr, ime: &InMemoryIndex) {
+        let base = &self.base;
+        // Directories are stored before the index which refers to them,
+        // and directories shared with other indices are stored only once
+        let mut nodes = Vec::new();
+        let mut encoding = Ok(());
+        ime.dir.to_tree(&mut |digest, node| {
+            if encoding.is_ok() {
+                encoding =
+                    encode_node(node).map(|node| nodes.push((tree_path(base, digest), node)));
+            }
         });
-        if ime.dirty {
-            let dir_s = String::try_from(&ime.dir).map_err(Error::SerialisingIndex)?;
-            let s_len = u64::try_from(dir_s.len())
-                .map_err(|_| Error::IndexTooLarge(name.into(), std::u64::MAX))?;
-            if s_len > MAX_METADATA_SIZE {
-                throw!(Error::IndexTooLarge(name.into(), s_len));
-            }
-            let index_path = self.base.join(INDICES).join(name);
-            let index_path_tmp = {
-                let mut ret = index_path.clone();
-                ret.set_extension("tmp");
-                ret
-            };
-            let mut fh = fs::OpenOptions::new()
-                .read(false)
-                .write(true)
-                .create_new(true)
-                .open(&index_path_tmp)
-                .await
-                .map_err(|e| Error::WritingIndex(index_path_tmp.to_owned(), e))?;
-            fh.write_all(dir_s.as_bytes())
-                .await
-                .map_err(|e| Error::WritingIndex(index_path_tmp.to_owned(), e))?;
-            // Complete any pending background IO
-            fh.flush()
-                .await
-                .map_err(|e| Error::WritingIndex(index_path_tmp.to_owned(), e))?;
-            // Having flushed we can drop the fh to know it's closed
-            drop(fh);
-            match fs::rename(&index_path_tmp, &index_path).await {
-                Err(e) => {
-                    fs::remove_file(&index_path_tmp).await.unwrap_or(());
-                    throw!(Error::WritingIndex(index_path.to_owned(), e))
-                }
-                Ok(()) => {}
+        encoding.map_err(Error::SerialisingIndex)?;
+        for (node_path, node) in nodes {
+            let len = node.len() as u64;
+            if matches!(self.config.max_index_size, Some(max) if len > max) {
+                throw!(Error::IndexTooLarge(name.into(), len));
+            }
+            if fs::metadata(&node_path).await.is_err() {
+                write_atomically(&node_path, &node[..])
+                    .await
+                    .map_err(|e| Error::WritingIndex(node_path.clone(), e))?;
             }
-            ime.dirty = false;
+        }
+        let mut encoded = INDEX_MAGIC.to_vec();
+        encoded.extend_from_slice(&TREE_INDEX_VERSION.to_le_bytes());
+        encoded.extend_from_slice(ime.digest.as_bytes());
+        let index_path = self.base.join(INDICES).join(name);
+        let index_path_tmp = temp_path(&index_path);
+        let mut fh = fs::OpenOptions::new()
+            .read(false)
+            .write(true)
+            .create_new(true)
+            .open(&index_path_tmp)
+            .await
+            .map_err(|e| Error::WritingIndex(index_path_tmp.to_owned(), e))?;
+        fh.write_all(&encoded)
+            .await
+            .map_err(|e| Error::WritingIndex(index_path_tmp.to_owned(), e))?;
+        // Complete any pending background IO
+        fh.flush()
+            .await
+            .map_err(|e| Error::WritingIndex(index_path_tmp.to_owned(), e))?;
+        // Having flushed we can drop the fh to know it's closed
+        drop(fh);
+        if let Err(e) = fs::rename(&index_path_tmp, &index_path).await {
+            fs::remove_file(&index_path_tmp).await.unwrap_or(());
+            throw!(Error::WritingIndex(index_path.to_owned(), e))
         }
     }
 
@@ -186,256 +761,3630 @@ impl SharedStorage {
         &self.base
     }
 
-    pub fn indices(&self) -> impl Iterator<Item = &OsStr> {
-        self.indices.keys().map(Deref::deref)
+    /// The names of the indices in the storage at the moment
+    pub fn indices(&self) -> impl Iterator<Item = OsString> {
+        let indices = self.indices.read().unwrap();
+        indices.keys().cloned().collect::<Vec<_>>().into_iter()
     }
 
+    /// Pick up any indices which other processes sharing the storage have
+    /// created, replaced or removed, returning how many indices changed
     #[throws(Error)]
-    pub async fn import<Claim, Name, Contents>(
-        &mut self,
-        name: Name,
-        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
-        content: Contents,
-    ) where
-        Name: AsRef<OsStr>,
-        Claim: ResourceAllocation + 'static,
-        Contents: Stream<Item = ImportEvent> + Unpin,
-    {
-        let name = name.as_ref();
-        let mut root = Directory::default();
-        let mut inserters: FuturesUnordered<
-            BoxFuture<Result<(Option<PathBuf>, OsString, StorageIdentifier), Error>>,
-        > = FuturesUnordered::new();
+    pub async fn refresh(&self) -> usize {
+        self.stats.lock().unwrap().data_changed();
+        self.reload_indices(true).await?
+    }
 
-        match self
-            .import_(content, &mut root, &mut inserters, provider)
+    /// Statistics about the data in the storage, and how much of the content
+    /// each index accounts for
+    ///
+    /// Statistics are kept between calls.  The data is counted once, and
+    /// the count kept up to date as data is added and removed, while indices
+    /// are only walked again once their content has changed.  As with the
+    /// indices themselves, changes made by other processes sharing the
+    /// storage are only noticed after a refresh.
+    #[throws(Error)]
+    pub async fn stats(&self) -> StorageStats {
+        let (blobs, bytes) = self.data_stats().await?;
+        StorageStats {
+            blobs,
+            bytes,
+            indices: self.index_stats(),
+        }
+    }
+
+    /// How many data files there are, and their size
+    #[throws(Error)]
+    async fn data_stats(&self) -> (usize, u64) {
+        let generation = {
+            let cache = self.stats.lock().unwrap();
+            if let Some(data) = cache.data {
+                return data;
+            }
+            cache.generation
+        };
+        let files = self
+            .scan_data()
             .await
-        {
-            Err(e) => {
-                while let Some(_) = inserters.next().await {}
-                throw!(e);
+            .map_err(|e| Error::GatheringStats(self.base.join(DATA), e))?;
+        let blobs = files
+            .iter()
+            .filter(|(file_path, _)| file_path.extension() != Some(OsStr::new("tmp")))
+            .count();
+        let data = (blobs, data_size(&files));
+        let mut cache = self.stats.lock().unwrap();
+        if cache.generation == generation {
+            cache.data = Some(data);
+        }
+        data
+    }
+
+    /// How much of the content each index accounts for
+    fn index_stats(&self) -> BTreeMap<OsString, IndexStats> {
+        let indices = self.indices.read().unwrap().clone();
+        let digests: BTreeMap<_, _> = indices
+            .iter()
+            .map(|(name, ime)| (name.clone(), ime.digest.clone()))
+            .collect();
+        let mut content = {
+            let cache = self.stats.lock().unwrap();
+            if let Some((cached, stats)) = &cache.indices {
+                if cached == &digests {
+                    return stats.clone();
+                }
             }
-            Ok(_) => {
-                while let Some((parent_path, file_name, identity)) =
-                    inserters.next().await.transpose()?
-                {
-                    if let Some(parent_path) = parent_path {
-                        root.traverse_mut(&parent_path, false)?
-                            .insert_file(file_name, identity)?;
-                    } else {
-                        root.insert_file(file_name, identity)?;
+            cache.content.clone()
+        };
+
+        // Only trees which have not been seen before need walking
+        content.retain(|digest, _| digests.values().any(|d| d == digest));
+        for ime in indices.values() {
+            content.entry(ime.digest.clone()).or_insert_with(|| {
+                let mut logical_bytes = 0;
+                let mut identities = HashSet::new();
+                for (_, identity) in ime.dir.walk() {
+                    logical_bytes += identity.size as u64;
+                    identities.insert(identity.clone());
+                }
+                Arc::new(TreeContent {
+                    logical_bytes,
+                    identities,
+                })
+            });
+        }
+        let mut users: HashMap<&StorageIdentifier, usize> = HashMap::new();
+        for digest in digests.values() {
+            for identity in &content[digest].identities {
+                *users.entry(identity).or_default() += 1;
+            }
+        }
+        let stats: BTreeMap<_, _> = digests
+            .iter()
+            .map(|(name, digest)| {
+                let tree = &content[digest];
+                let mut stats = IndexStats {
+                    logical_bytes: tree.logical_bytes,
+                    ..IndexStats::default()
+                };
+                for identity in &tree.identities {
+                    match users[identity] {
+                        1 => stats.unique_bytes += identity.size as u64,
+                        _ => stats.shared_bytes += identity.size as u64,
                     }
                 }
+                (name.clone(), stats)
+            })
+            .collect();
+
+        let mut cache = self.stats.lock().unwrap();
+        cache.indices = Some((digests, stats.clone()));
+        cache.content = content;
+        stats
+    }
+
+    /// The digest of the root directory of an index
+    ///
+    /// Two indices have the same content exactly when their digests are equal.
+    #[throws(Error)]
+    pub fn index_digest<Name: AsRef<OsStr>>(&self, name: Name) -> String {
+        self.index(name.as_ref())?.digest.clone()
+    }
+
+    /// The changes from one index to another, see [`Directory::diff`]
+    ///
+    /// Every change is found, while both indices are locked, before any is
+    /// returned, so the changes take as much memory as they need all at once
+    /// but are unaffected by the indices being replaced or removed later.
+    #[throws(Error)]
+    pub async fn diff<Old, New>(&self, old: Old, new: New) -> impl Iterator<Item = Change>
+    where
+        Old: AsRef<OsStr>,
+        New: AsRef<OsStr>,
+    {
+        let (old, new) = (old.as_ref(), new.as_ref());
+        let _old_index = self.lock_index(old, false).await?;
+        let _new_index = self.lock_index(new, false).await?;
+        let (old_ime, new_ime) = (self.index(old)?, self.index(new)?);
+        // Indices with the same digest have the same content
+        let changes: Vec<_> = match old_ime.digest == new_ime.digest {
+            true => vec![],
+            false => old_ime.dir.diff(&new_ime.dir).collect(),
+        };
+        self.record_use(old).await?;
+        self.record_use(new).await?;
+        changes.into_iter()
+    }
+
+    /// Describe an index as a Remote Execution API tree
+    #[throws(Error)]
+    pub fn reapi_tree<Name: AsRef<OsStr>>(&self, name: Name) -> reapi::Tree {
+        reapi::Tree::from_directory(&self.index(name.as_ref())?.dir)?
+    }
+
+    /// Create an index from a Remote Execution API tree
+    ///
+    /// The content of every file in the tree must already be in the storage,
+    /// though it may have been stored with a different executable bit, in
+    /// which case it is copied.  The new index must not already exist.
+    #[throws(Error)]
+    pub async fn import_reapi_tree<Name: AsRef<OsStr>>(&self, name: Name, tree: &reapi::Tree) {
+        let name = name.as_ref();
+        let _index = self.lock_index(name, true).await?;
+        self.check_absent(name).await?;
+        let _storage = self.lock_storage(false).await?;
+        let root = tree.to_directory()?;
+        for (_, identity) in root.walk() {
+            self.ensure_blob(identity).await?;
+        }
+
+        let root: InMemoryIndex = root.into();
+        self.save_index(name, &root).await?;
+        self.publish(name, root);
+        self.record_use(name).await?;
+        drop((_storage, _index));
+        self.evict_for(name).await;
+    }
+
+    /// Make sure the content for an identity is in the storage, copying it
+    /// from the same content with the other executable bit if need be
+    #[throws(Error)]
+    async fn ensure_blob(&self, identity: &StorageIdentifier) {
+        match self.find_blob(identity).await? {
+            Some(found) if &found == identity => {}
+            Some(other) => {
+                let content = self.open_blob(&other).await?;
+                store_blob(&self.base, &self.config, &self.stats, identity, content).await?;
             }
+            None => throw!(Error::BlobNotFound(identity.hash.clone())),
         }
-        assert!(inserters.is_empty());
-        drop(inserters);
+    }
 
-        let mut root: InMemoryIndex = root.into();
-        root.dirty = true;
-        self.indices.insert(name.to_owned(), root);
-        match self.save_index(name).await {
-            Ok(_) => {}
-            Err(e) => {
-                self.indices.remove(name);
-                throw!(e);
+    /// Find the content for an identity, whatever its executable bit,
+    /// preferring the identity itself
+    #[throws(Error)]
+    pub(crate) async fn find_blob(
+        &self,
+        identity: &StorageIdentifier,
+    ) -> Option<StorageIdentifier> {
+        let other =
+            StorageIdentifier::new(identity.hash.clone(), identity.size, !identity.executable);
+        for candidate in &[identity.clone(), other] {
+            if blob_present(&self.base, candidate)
+                .await
+                .map_err(|e| Error::ReadingBlob(candidate.filename(&self.base), e))?
+            {
+                return Some(candidate.clone());
             }
         }
+        None
     }
 
+    /// Insert content into the storage, unless it is already present
+    ///
+    /// The content is trusted to match the identity.
     #[throws(Error)]
-    async fn import_<'a, Contents, Claim>(
-        &'a mut self,
-        mut content: Contents,
-        root: &mut Directory,
-        inserters: &mut FuturesUnordered<
-            BoxFuture<'a, Result<(Option<PathBuf>, OsString, StorageIdentifier), Error>>,
-        >,
-        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
-    ) where
-        Contents: Stream<Item = ImportEvent> + Unpin,
-        Claim: ResourceAllocation + 'static,
+    pub(crate) async fn insert_blob<R>(&self, identity: &StorageIdentifier, content: R)
+    where
+        R: AsyncRead + Unpin,
     {
-        let mut event_ = content.next().await;
-        while let Some(event) = event_.take() {
-            // Before we do anything else, try and deal with any inserters
-            // who have completed
-            loop {
-                match futures::poll!(inserters.next()) {
-                    Poll::Ready(Some(Err(e))) => throw!(e),
-                    Poll::Ready(Some(Ok((parent_path, file_name, identity)))) => {
-                        if let Some(parent_path) = parent_path {
-                            root.traverse_mut(&parent_path, false)?
-                                .insert_file(file_name, identity)?;
-                        } else {
-                            root.insert_file(file_name, identity)?;
-                        }
-                    }
-                    _ => break,
-                }
+        let _storage = self.lock_storage(false).await?;
+        let present = blob_present(&self.base, identity)
+            .await
+            .map_err(|e| Error::IOErrorAddingToStorage(identity.filename(&self.base), e))?;
+        if !present {
+            store_blob(&self.base, &self.config, &self.stats, identity, content).await?;
+        }
+    }
+
+    /// Spool content for an identity which arrives in chunks into the
+    /// storage, as for a chunked file during an import
+    ///
+    /// Content which does not match the identity is not stored.
+    #[throws(Error)]
+    pub(crate) async fn spool_blob(
+        &self,
+        name: &Path,
+        identity: &StorageIdentifier,
+        chunks: mpsc::Receiver<Option<Bytes>>,
+    ) {
+        let _storage = self.lock_storage(false).await?;
+        let size = identity.size as u64;
+        let hash = Some(identity.hash.as_str());
+        let (base, config, stats) = (&self.base, &self.config, &self.stats);
+        Self::spool_chunks(base, config, stats, name, false, size, hash, chunks).await?;
+    }
+
+    /// Find the content with a hash, whatever its size or executable bit
+    #[throws(Error)]
+    pub(crate) async fn find_hash(&self, hash: &str) -> Option<StorageIdentifier> {
+        if !valid_hash(hash) {
+            return None;
+        }
+        let dir = self.base.join(DATA).join(&hash[0..2]).join(&hash[2..4]);
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => throw!(Error::ReadingBlob(dir, e)),
+        };
+        let mut found: Option<StorageIdentifier> = None;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| Error::ReadingBlob(dir.clone(), e))?
+        {
+            let identity = match parse_data_path(&self.base, &entry.path()) {
+                Some(identity) if identity.hash == hash => identity,
+                _ => continue,
+            };
+            // Prefer the content as it would be uploaded, not executable
+            if !identity.executable {
+                return Some(identity);
             }
-            match event {
-                ImportEvent::Error(e) => throw!(Error::ImportStreamError(e)),
-                ImportEvent::FileData(_) => throw!(Error::UnexpectedFileData),
-                ImportEvent::Directory(d) => {
-                    if let Some(dirname) = d.file_name() {
-                        if let Some(parent) = d.parent() {
-                            root.traverse_mut(parent, false)?.mkdir(dirname)?;
-                        } else {
-                            root.mkdir(dirname)?;
-                        };
+            found = Some(identity);
+        }
+        found
+    }
+
+    /// Read the action cache entry for an action's hash
+    ///
+    /// As with indices, the time of last use is kept as the modification
+    /// time of the entry, so that the least recently used entries are
+    /// evicted first.
+    #[throws(Error)]
+    pub(crate) async fn read_action(&self, hash: &str) -> Option<Vec<u8>> {
+        if !valid_hash(hash) {
+            return None;
+        }
+        let path = action_path(&self.base, hash);
+        let content = match fs::read(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => throw!(Error::ReadingBlob(path, e)),
+        };
+        match mark_used(&path, false).await {
+            Ok(()) => {}
+            // The entry was evicted or replaced since it was read
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => throw!(Error::TrackingUse(path, e)),
+        }
+        Some(content)
+    }
+
+    /// Replace the action cache entry for an action's hash
+    #[throws(Error)]
+    pub(crate) async fn write_action(&self, hash: &str, content: &[u8]) {
+        if !valid_hash(hash) {
+            throw!(Error::InvalidDigest(hash.into(), content.len() as i64));
+        }
+        let path = action_path(&self.base, hash);
+        write_atomically(&path, content)
+            .await
+            .map_err(|e| Error::IOErrorAddingToStorage(path, e))?;
+    }
+
+    /// Rewrite any indices stored in an older format in the current format,
+    /// returning how many were converted
+    #[throws(Error)]
+    pub async fn convert_indices(&self) -> usize {
+        let legacy: Vec<_> = self
+            .indices()
+            .filter(|name| matches!(self.index(name), Ok(ime) if ime.legacy))
+            .collect();
+        let mut converted = 0;
+        for name in &legacy {
+            let _index = self.lock_index(name, true).await?;
+            let _storage = self.lock_storage(false).await?;
+            // The index may have been replaced or removed meanwhile
+            let ime = match self.index(name) {
+                Ok(ime) if ime.legacy => ime,
+                _ => continue,
+            };
+            let ime = InMemoryIndex {
+                dir: ime.dir.clone(),
+                digest: ime.digest.clone(),
+                legacy: false,
+            };
+            self.save_index(name, &ime).await?;
+            self.publish(name, ime);
+            converted += 1;
+        }
+        converted
+    }
+
+    /// Export an index onto the filesystem
+    ///
+    /// The index's tree is recreated inside the target directory, which is
+    /// created if necessary.  Directories may already exist in the target,
+    /// but files must not.
+    #[throws(Error)]
+    pub async fn export_to_dir<Name, Target>(&self, name: Name, target: Target, mode: ExportMode)
+    where
+        Name: AsRef<OsStr>,
+        Target: AsRef<Path>,
+    {
+        let name = name.as_ref();
+        let target = target.as_ref();
+        let _index = self.lock_index(name, false).await?;
+        let ime = self.index(name)?;
+        self.record_use(name).await?;
+        fs::create_dir_all(target)
+            .await
+            .map_err(|e| Error::Exporting(target.to_owned(), e))?;
+        self.export_dir(&ime.dir, &mut target.to_owned(), mode)
+            .await?;
+    }
+
+    fn export_dir<'a>(
+        &'a self,
+        dir: &'a Directory,
+        target: &'a mut PathBuf,
+        mode: ExportMode,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            for (name, entry) in dir.iter() {
+                target.push(name);
+                match entry {
+                    DirectoryEntry::Directory(d) => {
+                        fs::create_dir_all(&target)
+                            .await
+                            .map_err(|e| Error::Exporting(target.clone(), e))?;
+                        self.export_dir(d, target, mode).await?;
+                    }
+                    DirectoryEntry::File(identity) => {
+                        self.export_file(identity, target, mode).await?;
+                    }
+                    DirectoryEntry::Symlink(link) => {
+                        symlink(link, target)
+                            .await
+                            .map_err(|e| Error::Exporting(target.clone(), e))?;
                     }
                 }
-                ImportEvent::File(parent_path, file_name, size, executable) => {
-                    // We're trying to insert this file, so first we need
-                    // an allocation in order to make this possible
-                    let mut alloc = loop {
-                        let maybe_alloc = provider.claim(size).await;
-                        match maybe_alloc {
-                            ResourceClaimResult::Impossible => throw!(Error::ImpossibleFileClaim(
-                                parent_path
-                                    .as_deref()
-                                    .unwrap_or(Path::new(""))
-                                    .join(file_name),
-                                size
-                            )),
-                            ResourceClaimResult::Busy => {
-                                if let Some((parent_path, file_name, identity)) =
-                                    inserters.next().await.transpose()?
-                                {
-                                    if let Some(parent_path) = parent_path {
-                                        root.traverse_mut(&parent_path, false)?
-                                            .insert_file(file_name, identity)?;
-                                    } else {
-                                        root.insert_file(file_name, identity)?;
-                                    }
-                                }
-                            }
-                            ResourceClaimResult::Ok(claim) => break claim,
-                        }
+                target.pop();
+            }
+            Ok(())
+        })
+    }
+
+    #[throws(Error)]
+    async fn export_file(&self, identity: &StorageIdentifier, target: &Path, mode: ExportMode) {
+        let export_err = |e| Error::Exporting(target.to_owned(), e);
+        let source = identity.filename(&self.base);
+        let raw = mode != ExportMode::Copy && fs::metadata(&source).await.is_ok();
+        match mode {
+            ExportMode::Hardlink if raw => {
+                // Data files written before they were made read-only are
+                // only made so now, and they share their mode with the link
+                set_read_only(&source, identity.executable)
+                    .await
+                    .map_err(export_err)?;
+                fs::hard_link(&source, target).await.map_err(export_err)?;
+                return;
+            }
+            ExportMode::Reflink if raw => {
+                let target = target.to_owned();
+                tokio::task::spawn_blocking(move || reflink(&source, &target))
+                    .await
+                    .map_err(io::Error::from)
+                    .and_then(|r| r)
+                    .map_err(export_err)?
+            }
+            _ => {
+                let mut input = self.open_blob(identity).await?;
+                let mut output = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(target)
+                    .await
+                    .map_err(export_err)?;
+                io::copy(&mut input, &mut output)
+                    .await
+                    .map_err(export_err)?;
+                output.flush().await.map_err(export_err)?;
+            }
+        }
+        set_executable(target, identity.executable)
+            .await
+            .map_err(export_err)?;
+    }
+
+    /// Export an index as a tarball
+    ///
+    /// The tarball is deterministic: entries are sorted by name, and ownership
+    /// and timestamps are fixed.  File content is read from the storage only as
+    /// it is written out, so memory use does not depend on file sizes.
+    #[throws(Error)]
+    pub async fn export_tar<Name, Writer>(&self, name: Name, writer: Writer) -> Writer
+    where
+        Name: AsRef<OsStr>,
+        Writer: AsyncWrite + Unpin,
+    {
+        fn flatten<'a>(
+            dir: &'a Directory,
+            prefix: &[u8],
+            entries: &mut Vec<(Vec<u8>, &'a DirectoryEntry)>,
+        ) {
+            let mut here: Vec<_> = dir.iter().collect();
+            here.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, entry) in here {
+                let mut path = prefix.to_vec();
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+                path.extend_from_slice(&os_str_bytes(name));
+                if let DirectoryEntry::Directory(d) = entry {
+                    entries.push((path.clone(), entry));
+                    flatten(d, &path, entries);
+                } else {
+                    entries.push((path, entry));
+                }
+            }
+        }
+
+        let name = name.as_ref();
+        let _index = self.lock_index(name, false).await?;
+        let ime = self.index(name)?;
+        self.record_use(name).await?;
+        let mut entries = Vec::new();
+        flatten(&ime.dir, b"", &mut entries);
+
+        let mut tarball = TarWriter::new(writer);
+        for (path, entry) in entries {
+            match entry {
+                DirectoryEntry::Directory(_) => tarball.append_dir(&path).await,
+                DirectoryEntry::Symlink(link) => {
+                    tarball.append_symlink(&path, &os_str_bytes(link)).await
+                }
+                DirectoryEntry::File(identity) => {
+                    let content = self.open_blob(identity).await?;
+                    tarball
+                        .append_file(&path, identity.size as u64, identity.executable, content)
+                        .await
+                }
+            }
+            .map_err(|e| Error::WritingTarball(name.into(), e))?;
+        }
+        tarball
+            .finish()
+            .await
+            .map_err(|e| Error::WritingTarball(name.into(), e))?
+    }
+
+    /// Open the content for an identity, whether stored raw or as chunks
+    #[throws(Error)]
+    pub(crate) async fn open_blob(&self, identity: &StorageIdentifier) -> ContentReader {
+        let blob = identity.filename(&self.base);
+        match fs::File::open(&blob).await {
+            Ok(file) => return Box::new(file) as ContentReader,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => throw!(Error::ReadingBlob(blob, e)),
+        }
+        let compressed = compressed_path(&self.base, identity);
+        match fs::File::open(&compressed).await {
+            Ok(file) => {
+                let decoder = ZstdDecoder::new(io::BufReader::new(file));
+                return Box::new(decoder) as ContentReader;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => throw!(Error::ReadingBlob(compressed, e)),
+        }
+        let list_path = chunk_list_path(&self.base, identity);
+        let chunks = match read_chunk_list(&list_path).await {
+            Ok(chunks) => chunks,
+            // Report the missing data under its usual name
+            Err(e) if e.kind() == io::ErrorKind::NotFound => throw!(Error::ReadingBlob(blob, e)),
+            Err(e) => throw!(Error::ReadingBlob(list_path, e)),
+        };
+        chunk_reader(&self.base, chunks)
+    }
+
+    /// Open a file inside an index for reading
+    #[throws(Error)]
+    pub async fn open<Name, P>(&self, name: Name, path: P) -> ContentReader
+    where
+        Name: AsRef<OsStr>,
+        P: AsRef<Path>,
+    {
+        let name = name.as_ref();
+        let _index = self.lock_index(name, false).await?;
+        let ime = self.index(name)?;
+        let content = self.open_blob(ime.dir.file(path)?).await?;
+        self.record_use(name).await?;
+        content
+    }
+
+    /// Read the entire content of a file inside an index
+    #[throws(Error)]
+    pub async fn read_to_bytes<Name, P>(&self, name: Name, path: P) -> Bytes
+    where
+        Name: AsRef<OsStr>,
+        P: AsRef<Path>,
+    {
+        let name = name.as_ref();
+        let _index = self.lock_index(name, false).await?;
+        let ime = self.index(name)?;
+        let identity = ime.dir.file(path)?;
+        let mut content = Vec::with_capacity(identity.size);
+        self.open_blob(identity)
+            .await?
+            .read_to_end(&mut content)
+            .await
+            .map_err(|e| Error::ReadingBlob(identity.filename(&self.base), e))?;
+        self.record_use(name).await?;
+        content.into()
+    }
+
+    /// Remove an index from the storage
+    ///
+    /// This deletes the index file and forgets the in-memory copy of the index.
+    /// No data files are removed, for that you need to collect garbage.
+    #[throws(Error)]
+    pub async fn remove_index<Name: AsRef<OsStr>>(&self, name: Name) {
+        let name = name.as_ref();
+        self.index(name)?;
+        let _index = self.lock_index(name, true).await?;
+        self.delete_index(name).await?;
+    }
+
+    /// When an index was last imported, read or exported
+    ///
+    /// Indices which have not been used since they were created count as
+    /// used when their index file was last written.
+    #[throws(Error)]
+    pub async fn last_used<Name: AsRef<OsStr>>(&self, name: Name) -> SystemTime {
+        let name = name.as_ref();
+        self.index(name)?;
+        let used_path = self.base.join(USED).join(name);
+        let meta = match fs::metadata(&used_path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let index_path = self.base.join(INDICES).join(name);
+                fs::metadata(&index_path)
+                    .await
+                    .map_err(|e| Error::TrackingUse(index_path, e))?
+            }
+            Err(e) => throw!(Error::TrackingUse(used_path, e)),
+        };
+        meta.modified()
+            .map_err(|e| Error::TrackingUse(used_path, e))?
+    }
+
+    /// Merge several existing indices together to form a new index
+    ///
+    /// The sources are merged in the order given, with conflicts between them
+    /// resolved according to the policy.  The sources are locked while they
+    /// are read, and the new index must not already exist.
+    #[throws(Error)]
+    pub async fn merge<Name, Source>(&self, name: Name, sources: &[Source], policy: MergePolicy)
+    where
+        Name: AsRef<OsStr>,
+        Source: AsRef<OsStr>,
+    {
+        let name = name.as_ref();
+        let mut locked: BTreeSet<&OsStr> = sources.iter().map(AsRef::as_ref).collect();
+        if !locked.insert(name) {
+            throw!(Error::IndexExists(name.into()));
+        }
+        // Indices are locked in order of name, so that merges running at
+        // once cannot deadlock one another
+        let mut _indices = Vec::with_capacity(locked.len());
+        for index in locked {
+            _indices.push(self.lock_index(index, index == name).await?);
+        }
+        self.check_absent(name).await?;
+        let _storage = self.lock_storage(false).await?;
+        let mut root = Directory::default();
+        for source in sources {
+            let source = source.as_ref();
+            root.merge(&self.index(source)?.dir, policy)?;
+        }
+
+        let root: InMemoryIndex = root.into();
+        self.save_index(name, &root).await?;
+        self.publish(name, root);
+        for source in sources {
+            self.record_use(source.as_ref()).await?;
+        }
+        self.record_use(name).await?;
+        drop((_storage, _indices));
+        self.evict_for(name).await;
+    }
+
+    /// Derive a new index from an existing one, without importing the whole
+    /// tree again
+    ///
+    /// The edits are made to the content of the base index in order, and
+    /// then the content is imported over the result, replacing any files and
+    /// symbolic links it conflicts with.  The content may add to directories
+    /// and hard link to files which are only in the edited base, but a
+    /// directory must be deleted by an edit before the content can replace
+    /// it.  Only the content needs importing, so if there is none then the
+    /// content can simply be an empty stream.  The base index is left as it
+    /// is, and the new index must not already exist.
+    #[throws(Error)]
+    pub async fn derive<Claim, Base, Name, Contents>(
+        &self,
+        base: Base,
+        name: Name,
+        edits: &[IndexEdit],
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+        content: Contents,
+    ) where
+        Base: AsRef<OsStr>,
+        Name: AsRef<OsStr>,
+        Claim: ResourceAllocation + 'static,
+        Contents: Stream<Item = ImportEvent> + Unpin,
+    {
+        let (base, name) = (base.as_ref(), name.as_ref());
+        if base == name {
+            throw!(Error::IndexExists(name.into()));
+        }
+        // Indices are locked in order of name, so that derivations running
+        // at once cannot deadlock one another
+        let (_base_index, _index) = if base < name {
+            let base_lock = self.lock_index(base, false).await?;
+            (base_lock, self.lock_index(name, true).await?)
+        } else {
+            let name_lock = self.lock_index(name, true).await?;
+            (self.lock_index(base, false).await?, name_lock)
+        };
+        self.check_absent(name).await?;
+        let _storage = self.lock_storage(false).await?;
+        let mut root = self.index(base)?.dir.clone();
+        for edit in edits {
+            match edit {
+                IndexEdit::Delete(path) => {
+                    root.remove(path)?;
+                }
+                IndexEdit::Mkdir(path) => {
+                    root.traverse_mut(path, true)?;
+                }
+                IndexEdit::SetExecutable(path, executable) => {
+                    let identity = StorageIdentifier {
+                        executable: *executable,
+                        ..root.file(path)?.clone()
                     };
-                    // We have an allocation, let's draw the next event
-                    // which must be file data
-                    match content.next().await {
-                        None => {
-                            alloc.release().await;
-                            throw!(Error::UnexpectedEndOfContent)
-                        }
-                        Some(ImportEvent::FileData(bytes)) => {
-                            // We have an allocation, we have the bytes, let's
-                            // spawn our future
-                            inserters.push(
-                                tokio::task::spawn(Self::import_file(
-                                    alloc,
-                                    self.base().to_owned(),
-                                    parent_path,
-                                    file_name,
-                                    executable,
-                                    bytes,
-                                ))
-                                .map(|r| r.unwrap_or_else(|e| Err(Error::JoinError(e))))
-                                .boxed(),
-                            );
-                        }
-                        Some(ImportEvent::Error(e)) => throw!(Error::ImportStreamError(e)),
-                        _ => {
-                            alloc.release().await;
-                            throw!(Error::ExpectedFileDataEvent)
-                        }
-                    }
+                    self.ensure_blob(&identity).await?;
+                    root.remove(path)?;
+                    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+                    // The path names a file, so it has a file name
+                    root.traverse_mut(parent, false)?
+                        .insert_file(path.file_name().unwrap(), identity)?;
                 }
             }
-            event_ = content.next().await;
         }
+        let imported = self.import_tree(&root, provider, content).await?;
+        root.merge(&imported, MergePolicy::LastWins)?;
+
+        let root: InMemoryIndex = root.into();
+        self.save_index(name, &root).await?;
+        self.publish(name, root);
+        self.record_use(base).await?;
+        self.record_use(name).await?;
+        drop((_storage, _index, _base_index));
+        self.evict_for(name).await;
     }
 
+    /// Protect content from garbage collection until the lease expires
     #[throws(Error)]
-    async fn import_file(
-        mut allocation: impl ResourceAllocation,
-        base_path: PathBuf,
-        parent_path: Option<PathBuf>,
-        file_name: OsString,
-        executable: bool,
-        contents: Bytes,
-    ) -> (Option<PathBuf>, OsString, StorageIdentifier) {
-        use bytes::Buf;
-        // Rough approach is as follows...
-        // First we compute the identifier for the input data and decide
-        // if we already have it.
-        let size = contents.len();
-        let hash = tokio::task::block_in_place(|| {
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.input(contents.bytes());
-            let result = hasher.result();
-            format!("{:x}", result)
-        });
-        let identity = StorageIdentifier {
-            hash,
-            size,
-            executable,
+    pub async fn lease(&self, target: LeaseTarget, ttl: Duration) -> Lease {
+        // The index cannot be removed until its content is leased
+        let (_index, trees, blobs) = match target {
+            LeaseTarget::Index(name) => {
+                let lock = self.lock_index(&name, false).await?;
+                (Some(lock), vec![self.index(&name)?.digest.clone()], vec![])
+            }
+            LeaseTarget::Identifiers(blobs) => (None, vec![], blobs),
         };
-        // Next we need to see if we need to insert it into the store
-        // to do that we determine the file name and then see if we can stat it
-        let entry_path = identity.filename(&base_path);
-        match fs::metadata(&entry_path).await {
-            Ok(_) => {}
-            Err(e) => {
-                if e.kind() == io::ErrorKind::NotFound {
-                    // The file wasn't present, so we need to prepare the
-                    // prefix directories
-                    let parent = entry_path.parent().unwrap();
-                    fs::create_dir_all(parent)
-                        .await
-                        .map_err(|e| Error::IOErrorAddingToStorage(entry_path.clone(), e))?;
-                    let mut temp_file = entry_path.clone();
-                    temp_file.set_extension("tmp");
-                    let mut fh = fs::OpenOptions::new()
-                        .read(false)
-                        .write(true)
-                        .create_new(true)
-                        .open(&temp_file)
-                        .await
-                        .map_err(|e| Error::IOErrorAddingToStorage(temp_file.clone(), e))?;
-                    fh.write_all(contents.bytes())
-                        .await
-                        .map_err(|e| Error::IOErrorAddingToStorage(temp_file.clone(), e))?;
-                    // Complete any pending background IO
-                    fh.flush()
-                        .await
-                        .map_err(|e| Error::IOErrorAddingToStorage(temp_file.clone(), e))?;
-                    // Having flushed we can drop the fh to know it's closed
-                    drop(fh);
-                    fs::rename(&temp_file, &entry_path)
-                        .await
-                        .map_err(|e| Error::IOErrorAddingToStorage(temp_file.clone(), e))?;
-                } else {
-                    throw!(Error::IOErrorAddingToStorage(entry_path, e));
+        // Nor can garbage be collected until the lease is recorded
+        let _storage = self.lock_storage(false).await?;
+        let expires = SystemTime::now() + ttl;
+        let record = LeaseRecord {
+            expires,
+            trees,
+            blobs,
+        };
+        let encoded = bincode::DefaultOptions::new()
+            .serialize(&record)
+            .map_err(Error::SerialisingLease)?;
+        let id = format!(
+            "{}-{}-{}",
+            expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            std::process::id(),
+            SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.base.join(LEASES).join(&id);
+        write_atomically(&path, &encoded[..])
+            .await
+            .map_err(|e| Error::UpdatingLease(path, e))?;
+        Lease { id, expires }
+    }
+
+    /// Release a lease before it expires
+    ///
+    /// Releasing a lease which has already expired is not an error.
+    #[throws(Error)]
+    pub async fn release(&self, lease: &Lease) {
+        let path = self.base.join(LEASES).join(&lease.id);
+        match fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => throw!(Error::UpdatingLease(path, e)),
+        }
+    }
+
+    /// The leases in the storage which have yet to expire
+    #[throws(Error)]
+    pub async fn leases(&self) -> Vec<Lease> {
+        let now = SystemTime::now();
+        self.read_leases()
+            .await?
+            .0
+            .into_iter()
+            .map(|(lease, _)| lease)
+            .filter(|lease| lease.expires > now)
+            .collect()
+    }
+
+    /// Read every lease in the storage, whether or not it has expired, along
+    /// with the paths of any lease files which could not be decoded
+    #[throws(Error)]
+    async fn read_leases(&self) -> (Vec<(Lease, LeaseRecord)>, Vec<PathBuf>) {
+        let dir = self.base.join(LEASES);
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .map_err(|e| Error::ReadingLease(dir.clone(), e))?;
+        let mut leases = Vec::new();
+        let mut broken = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| Error::ReadingLease(dir.clone(), e))?
+        {
+            let path = entry.path();
+            if path.extension() == Some(OsStr::new("tmp")) {
+                continue;
+            }
+            let id = match entry.file_name().into_string() {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            let content = match fs::read(&path).await {
+                Ok(content) => content,
+                // The lease was released since the directory was read
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => throw!(Error::ReadingLease(path, e)),
+            };
+            let record: LeaseRecord = match bincode::DefaultOptions::new().deserialize(&content) {
+                Ok(record) => record,
+                Err(_) => {
+                    broken.push(path);
+                    continue;
+                }
+            };
+            let expires = record.expires;
+            leases.push((Lease { id, expires }, record));
+        }
+        (leases, broken)
+    }
+
+    /// Gather what garbage collection must keep
+    ///
+    /// A lease which cannot be read, or which names a directory no longer in
+    /// the storage, protects whatever else of its content can be found,
+    /// rather than preventing garbage from being collected at all.
+    #[throws(Error)]
+    async fn roots(&self) -> Roots {
+        let (leases, broken_leases) = self.read_leases().await?;
+        let mut roots = Roots {
+            indices: self.indices.read().unwrap().clone(),
+            trees: Vec::new(),
+            blobs: Vec::new(),
+            expired: Vec::new(),
+            broken_leases,
+        };
+        let now = SystemTime::now();
+        for (lease, record) in leases {
+            if lease.expires <= now {
+                roots.expired.push(lease);
+                continue;
+            }
+            let mut broken = false;
+            for digest in &record.trees {
+                match self.load_tree(digest).await {
+                    Ok(dir) => roots.trees.push(dir),
+                    Err(Error::MissingTree(_))
+                    | Err(Error::CorruptTree(_))
+                    | Err(Error::DecodingIndex(..))
+                    | Err(Error::IndexTooLarge(..)) => broken = true,
+                    Err(e) => throw!(e),
                 }
             }
+            if broken {
+                roots
+                    .broken_leases
+                    .push(self.base.join(LEASES).join(&lease.id));
+            }
+            roots.blobs.extend(record.blobs);
         }
+        roots.broken_leases.sort();
+        roots
+    }
 
-        // Clean up our memory usage
-        drop(contents);
-        allocation.release().await;
-        (parent_path, file_name, identity)
+    /// Load a directory, with all its content, from the trees directory
+    #[throws(Error)]
+    async fn load_tree(&self, digest: &str) -> Directory {
+        let base = self.base.clone();
+        let digest = digest.to_owned();
+        let max_size = self.config.max_index_size;
+        tokio::task::spawn_blocking(move || {
+            let mut load = |digest: &str| read_node(&base, digest, max_size);
+            Directory::from_tree(&digest, &mut load)
+        })
+        .await??
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::SharedStorage;
+    /// Remove any data files which are not referenced by an index or by a
+    /// lease which has yet to expire, and remove expired leases
+    ///
+    /// Temporary files are left alone since they may belong to an import
+    /// which is currently in progress.  Other processes sharing the storage
+    /// are excluded while garbage is collected, and any indices they have
+    /// changed are picked up first.
+    #[throws(Error)]
+    pub async fn collect_garbage(&self) -> GarbageReport {
+        let _storage = self.lock_storage(true).await?;
+        self.reload_indices(false).await?;
+        let roots = self.roots().await?;
+        let (mut report, _) = self.sweep(&roots, None).await?;
+        for lease in &roots.expired {
+            self.release(lease).await?;
+            report.leases_expired += 1;
+        }
+        report.broken_leases = roots.broken_leases;
+        report
+    }
 
-    #[tokio::test]
-    async fn create_twice() {
-        let td = tempfile::tempdir().expect("Unable to create tempdir");
-        let ss = SharedStorage::new(&td)
+    /// Evict the least recently used indices and action results, if there is
+    /// more data in the storage than its quota allows, until the data is
+    /// within the low-water mark of the quota
+    ///
+    /// Evicted indices are removed just as by `remove_index`, along with the
+    /// data and directories which nothing else refers to.  Other garbage,
+    /// such as content uploaded by a client but not yet referenced by an
+    /// index, is left for `collect_garbage`.  Indices whose content is leased
+    /// are never evicted, nor are indices which any process is using at the
+    /// time.  This is done whenever an index is created, so need only be
+    /// called to enforce a quota which has been lowered, to make room for
+    /// content stored other than by creating an index, or to find out why
+    /// eviction is failing.  Without a quota, nothing is done at all.
+    #[throws(Error)]
+    pub async fn enforce_quota(&self) -> EvictionReport {
+        self.evict(None).await?
+    }
+
+    /// Enforce the quota once an index has been created, without evicting it
+    ///
+    /// The index is in place whether or not this succeeds, so a failure is
+    /// not reported to its creator.  The storage stays over its quota until
+    /// eviction next succeeds, and `enforce_quota` reports the failure if it
+    /// persists.
+    async fn evict_for(&self, name: &OsStr) {
+        let _ = self.evict(Some(name)).await;
+    }
+
+    /// Enforce the quota, without evicting the index just created, if any
+    #[throws(Error)]
+    async fn evict(&self, spare: Option<&OsStr>) -> EvictionReport {
+        let mut report = EvictionReport::default();
+        let quota = match self.config.quota {
+            Some(quota) => quota,
+            None => return report,
+        };
+        let _storage = self.lock_storage(true).await?;
+        let files = self
+            .scan_data()
             .await
-            .expect("Unable to create storage");
-        drop(ss);
-        let ss = SharedStorage::new(&td)
+            .map_err(|e| Error::CollectingGarbage(self.base.join(DATA), e))?;
+        let actions_dir = self.base.join(ACTIONS);
+        let actions = scan_objects(&actions_dir)
             .await
-            .expect("Unable to create storage a second time");
-        drop(ss);
+            .map_err(|e| Error::CollectingGarbage(actions_dir, e))?;
+        let mut used = data_size(&files) + data_size(&actions);
+        if used > quota.limit {
+            self.reload_indices(false).await?;
+            let mut roots = self.roots().await?;
+            report.garbage.broken_leases = roots.broken_leases.clone();
+            let now = SystemTime::now();
+            let mut pinned = HashSet::new();
+            for (lease, record) in self.read_leases().await?.0 {
+                if lease.expires > now {
+                    pinned.extend(record.trees);
+                }
+            }
+            let mut candidates = Vec::new();
+            for (name, ime) in &roots.indices {
+                if Some(name.as_os_str()) != spare && !pinned.contains(&ime.digest) {
+                    let victim = Victim::Index(name.clone());
+                    candidates.push((self.last_used(name).await?, victim));
+                }
+            }
+            for (path, size) in actions {
+                if path.extension() == Some(OsStr::new("tmp")) {
+                    continue;
+                }
+                let used_err = |e| Error::TrackingUse(path.clone(), e);
+                let last_used = match fs::metadata(&path).await {
+                    Ok(meta) => meta.modified().map_err(used_err)?,
+                    // The entry was replaced since the actions were scanned
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => throw!(used_err(e)),
+                };
+                candidates.push((last_used, Victim::Action(path, size)));
+            }
+            candidates.sort();
+            for (_, victim) in candidates {
+                if used <= quota.low_water {
+                    break;
+                }
+                let name = match victim {
+                    Victim::Index(name) => name,
+                    Victim::Action(path, size) => {
+                        remove_object(&path)
+                            .await
+                            .map_err(|e| Error::CollectingGarbage(path, e))?;
+                        report.actions_evicted += 1;
+                        used -= size;
+                        continue;
+                    }
+                };
+                // Waiting for an index lock here could deadlock, and an
+                // index which is locked is in use anyway
+                let _index = match self.try_lock_index(&name)? {
+                    Some(lock) => lock,
+                    None => continue,
+                };
+                self.delete_index(&name).await?;
+                let evicted = match roots.indices.remove(&name) {
+                    Some(evicted) => evicted,
+                    None => continue,
+                };
+                let (garbage, _) = self.sweep(&roots, Some(&evicted.dir)).await?;
+                report.garbage.blobs_freed += garbage.blobs_freed;
+                report.garbage.bytes_freed += garbage.bytes_freed;
+                report.garbage.trees_freed += garbage.trees_freed;
+                report.indices_evicted.push(name);
+                used -= garbage.bytes_freed;
+            }
+        }
+        report.bytes_used = used;
+        report
+    }
+
+    /// Remove the data and directories which nothing kept refers to, while
+    /// holding the storage lock exclusively, returning how many bytes of data
+    /// remain
+    ///
+    /// If some content has been released, only what it referred to is
+    /// removed, and any other garbage is left alone.  Temporary files are
+    /// neither removed nor counted.
+    #[throws(Error)]
+    async fn sweep(&self, roots: &Roots, released: Option<&Directory>) -> (GarbageReport, u64) {
+        let gc_err = |p: &Path| {
+            let p = p.to_owned();
+            move |e| Error::CollectingGarbage(p, e)
+        };
+        let mut report = GarbageReport::default();
+
+        let files = self
+            .scan_data()
+            .await
+            .map_err(gc_err(&self.base.join(DATA)))?;
+        let chunk_lists: HashSet<_> = files
+            .iter()
+            .map(|(file_path, _)| file_path)
+            .filter(|file_path| file_path.extension() == Some(OsStr::new(CHUNKS)))
+            .cloned()
+            .collect();
+        let referenced = self.data_files(roots.identities(), &chunk_lists).await?;
+        let released_files = match released {
+            Some(dir) => {
+                let identities = dir.walk().map(|(_, identity)| identity);
+                Some(self.data_files(identities, &chunk_lists).await?)
+            }
+            None => None,
+        };
+
+        let mut used = 0;
+        for (file_path, size) in files {
+            if file_path.extension() == Some(OsStr::new("tmp")) {
+                continue;
+            }
+            let stem = file_path.with_extension("");
+            if referenced.contains(&stem)
+                || matches!(&released_files, Some(released) if !released.contains(&stem))
+            {
+                used += size;
+                continue;
+            }
+            remove_object(&file_path)
+                .await
+                .map_err(gc_err(&file_path))?;
+            self.stats.lock().unwrap().data_removed(size);
+            report.blobs_freed += 1;
+            report.bytes_freed += size;
+        }
+
+        // Directories are referenced by their digests, from any index
+        let trees = scan_objects(&self.base.join(TREES))
+            .await
+            .map_err(gc_err(&self.base.join(TREES)))?;
+        let tree_paths = |dirs: &mut dyn Iterator<Item = &Directory>| {
+            let mut paths = HashSet::new();
+            for dir in dirs {
+                dir.to_tree(&mut |digest, _| {
+                    paths.insert(tree_path(&self.base, digest));
+                });
+            }
+            paths
+        };
+        let referenced = tree_paths(&mut roots.dirs());
+        let released_trees = released.map(|dir| tree_paths(&mut std::iter::once(dir)));
+        for (file_path, _) in trees {
+            if file_path.extension() == Some(OsStr::new("tmp"))
+                || referenced.contains(&file_path)
+                || matches!(&released_trees, Some(released) if !released.contains(&file_path))
+            {
+                continue;
+            }
+            remove_object(&file_path)
+                .await
+                .map_err(gc_err(&file_path))?;
+            report.trees_freed += 1;
+        }
+        (report, used)
+    }
+
+    /// The data files holding some content, named without any extension
+    ///
+    /// Data files are referenced by their name without any extension, and
+    /// where content is stored in one of the given chunk lists, so are its
+    /// chunks.
+    #[throws(Error)]
+    async fn data_files<'a, Identities>(
+        &self,
+        identities: Identities,
+        chunk_lists: &HashSet<PathBuf>,
+    ) -> HashSet<PathBuf>
+    where
+        Identities: Iterator<Item = &'a StorageIdentifier>,
+    {
+        let mut files: HashSet<_> = identities
+            .map(|identity| identity.filename(&self.base))
+            .collect();
+        let mut chunks = Vec::new();
+        for file in &files {
+            let list_path = file.with_extension(CHUNKS);
+            if chunk_lists.contains(&list_path) {
+                let list = read_chunk_list(&list_path)
+                    .await
+                    .map_err(|e| Error::CollectingGarbage(list_path, e))?;
+                chunks.extend(list.iter().map(|chunk| chunk.filename(&self.base)));
+            }
+        }
+        files.extend(chunks);
+        files
+    }
+
+    /// Compress any data in the storage which is stored raw
+    ///
+    /// Each file is replaced by its compressed equivalent atomically, so
+    /// this is safe to run while the storage is in use.  Exports which hard
+    /// linked to the raw data are unaffected.
+    #[throws(Error)]
+    pub async fn compress_blobs(&self, level: u32) -> CompressionReport {
+        let _storage = self.lock_storage(false).await?;
+        let compress_err = |p: &Path| {
+            let p = p.to_owned();
+            move |e| Error::CompressingBlob(p, e)
+        };
+        let files = self
+            .scan_data()
+            .await
+            .map_err(compress_err(&self.base.join(DATA)))?;
+        let mut report = CompressionReport::default();
+        // Raw data files are the only ones with no extension
+        for (file_path, size) in files.into_iter().filter(|f| f.0.extension().is_none()) {
+            let raw = fs::File::open(&file_path)
+                .await
+                .map_err(compress_err(&file_path))?;
+            let compressed = file_path.with_extension(COMPRESSED);
+            let compressed_size = write_atomically(&compressed, compressor(raw, level))
+                .await
+                .map_err(compress_err(&compressed))?;
+            fs::remove_file(&file_path)
+                .await
+                .map_err(compress_err(&file_path))?;
+            {
+                let mut stats = self.stats.lock().unwrap();
+                stats.data_added(compressed_size);
+                stats.data_removed(size);
+            }
+            report.blobs_compressed += 1;
+            report.bytes_before += size;
+            report.bytes_after += compressed_size;
+        }
+        report
+    }
+
+    /// Check the integrity of the storage
+    ///
+    /// Every data file is read back and compared with the hash and size in
+    /// its name, every identifier referenced by an index is checked to be
+    /// present, and every index file is checked to be parsable.  Nothing in
+    /// the storage is changed.
+    #[throws(Error)]
+    pub async fn fsck(&self) -> FsckReport {
+        self.check().await?.0
+    }
+
+    /// Check the integrity of the storage, repairing what can be repaired
+    ///
+    /// Corrupt data files are moved into the quarantine directory, as are
+    /// chunk lists whose chunks are damaged.  The content is then imported,
+    /// without creating an index, to repopulate any data which is missing or
+    /// was quarantined.  If there is nothing to repopulate from then the
+    /// content can simply be an empty stream.  Any data in the content which
+    /// was not needed is left for garbage collection.
+    ///
+    /// Other processes sharing the storage are excluded during the repair,
+    /// but indices they have changed are only considered after a refresh.
+    #[throws(Error)]
+    pub async fn repair<Claim, Contents>(
+        &self,
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+        content: Contents,
+    ) -> FsckReport
+    where
+        Claim: ResourceAllocation + 'static,
+        Contents: Stream<Item = ImportEvent> + Unpin,
+    {
+        let _storage = self.lock_storage(true).await?;
+        let (mut report, broken_lists) = self.check().await?;
+        let mut damaged: BTreeSet<_> = report.missing.drain(..).collect();
+        for path in report.corrupt.iter().chain(&broken_lists) {
+            self.quarantine(path).await?;
+            damaged.extend(parse_data_path(&self.base, path));
+        }
+        self.import_tree(&Directory::default(), provider, content)
+            .await?;
+
+        // Quarantined data might not have been referenced in the first place
+        let (referenced, _) = self.referenced().await?;
+        for identity in damaged {
+            let present = blob_present(&self.base, &identity)
+                .await
+                .map_err(|e| Error::CheckingStorage(identity.filename(&self.base), e))?;
+            if present {
+                report.repaired.push(identity);
+            } else if referenced.contains(&identity) {
+                report.missing.push(identity);
+            }
+        }
+        report
+    }
+
+    /// Check the integrity of the storage, returning the report and the chunk
+    /// lists which refer to missing or corrupt chunks
+    #[throws(Error)]
+    async fn check(&self) -> (FsckReport, Vec<PathBuf>) {
+        let check_err = |p: &Path| {
+            let p = p.to_owned();
+            move |e| Error::CheckingStorage(p, e)
+        };
+        let mut report = FsckReport::default();
+
+        let indices = self.base.join(INDICES);
+        let mut indexfiles = fs::read_dir(&indices).await.map_err(check_err(&indices))?;
+        while let Some(entry) = indexfiles.next_entry().await.map_err(check_err(&indices))? {
+            let index_path = entry.path();
+            let meta = entry.metadata().await.map_err(check_err(&index_path))?;
+            if !meta.is_file() {
+                continue;
+            }
+            if index_path.extension() == Some(OsStr::new("tmp")) {
+                report.stray_temporaries.push(index_path);
+                continue;
+            }
+            match read_index(&self.base, &index_path, self.config.max_index_size).await {
+                Ok(_) => {}
+                Err(Error::Preparing(e)) if e.kind() != io::ErrorKind::InvalidData => {
+                    throw!(Error::CheckingStorage(index_path, e))
+                }
+                Err(_) => report.bad_indices.push(index_path),
+            }
+        }
+
+        // Chunked imports are spooled directly inside the data directory
+        let data = self.base.join(DATA);
+        let mut spooled = fs::read_dir(&data).await.map_err(check_err(&data))?;
+        while let Some(entry) = spooled.next_entry().await.map_err(check_err(&data))? {
+            if entry.path().extension() == Some(OsStr::new("tmp")) {
+                report.stray_temporaries.push(entry.path());
+            }
+        }
+
+        // Data stored as single files is verified first, so that chunk lists
+        // can be verified knowing their chunks are sound
+        let files = self.scan_data().await.map_err(check_err(&data))?;
+        let mut stored = Vec::new();
+        let mut lists = Vec::new();
+        let mut corrupt = HashSet::new();
+        for (file_path, _) in files {
+            let extension = file_path.extension().and_then(OsStr::to_str);
+            if extension == Some("tmp") {
+                report.stray_temporaries.push(file_path);
+                continue;
+            }
+            let identity = match parse_data_path(&self.base, &file_path) {
+                Some(identity) => identity,
+                None => {
+                    report.orphans.push(file_path);
+                    continue;
+                }
+            };
+            let sound = match extension {
+                None => {
+                    let file = fs::File::open(&file_path)
+                        .await
+                        .map_err(check_err(&file_path))?;
+                    content_matches(&identity, file).await
+                }
+                Some(COMPRESSED) => {
+                    let file = fs::File::open(&file_path)
+                        .await
+                        .map_err(check_err(&file_path))?;
+                    content_matches(&identity, ZstdDecoder::new(io::BufReader::new(file))).await
+                }
+                Some(CHUNKS) => {
+                    lists.push((file_path.clone(), identity.clone()));
+                    true
+                }
+                _ => {
+                    report.orphans.push(file_path);
+                    continue;
+                }
+            };
+            if !sound {
+                corrupt.insert(identity.clone());
+                report.corrupt.push(file_path.clone());
+            }
+            stored.push((file_path, identity));
+        }
+
+        let mut broken_lists = Vec::new();
+        for (list_path, identity) in lists {
+            let chunks = match read_chunk_list(&list_path).await {
+                Ok(chunks) => chunks,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    report.corrupt.push(list_path);
+                    continue;
+                }
+                Err(e) => throw!(Error::CheckingStorage(list_path, e)),
+            };
+            let mut damaged = false;
+            for chunk in &chunks {
+                damaged |= corrupt.contains(chunk)
+                    || !blob_present(&self.base, chunk)
+                        .await
+                        .map_err(check_err(&chunk.filename(&self.base)))?;
+            }
+            if damaged {
+                broken_lists.push(list_path);
+            } else if !content_matches(&identity, chunk_reader(&self.base, chunks)).await {
+                report.corrupt.push(list_path);
+            }
+        }
+
+        let (referenced, broken_leases) = self.referenced().await?;
+        report.broken_leases = broken_leases;
+        for identity in &referenced {
+            let present = blob_present(&self.base, identity)
+                .await
+                .map_err(check_err(&identity.filename(&self.base)))?;
+            if !present {
+                report.missing.push(identity.clone());
+            }
+        }
+        report.orphans.extend(
+            stored
+                .into_iter()
+                .filter(|(_, identity)| !referenced.contains(identity))
+                .map(|(file_path, _)| file_path),
+        );
+
+        report.missing.sort();
+        report.corrupt.sort();
+        report.orphans.sort();
+        report.stray_temporaries.sort();
+        report.bad_indices.sort();
+        (report, broken_lists)
+    }
+
+    /// Every identity referenced by an index or by a lease which has yet to
+    /// expire, including the chunks of any referenced data which is stored
+    /// as a list of chunks, along with any leases which are broken
+    ///
+    /// Chunk lists which are malformed are skipped.
+    #[throws(Error)]
+    async fn referenced(&self) -> (HashSet<StorageIdentifier>, Vec<PathBuf>) {
+        let roots = self.roots().await?;
+        let mut referenced: HashSet<_> = roots.identities().cloned().collect();
+        let mut chunks = Vec::new();
+        for identity in &referenced {
+            let list_path = chunk_list_path(&self.base, identity);
+            match read_chunk_list(&list_path).await {
+                Ok(list) => chunks.extend(list),
+                Err(e)
+                    if e.kind() == io::ErrorKind::NotFound
+                        || e.kind() == io::ErrorKind::InvalidData => {}
+                Err(e) => throw!(Error::CheckingStorage(list_path, e)),
+            }
+        }
+        referenced.extend(chunks);
+        (referenced, roots.broken_leases)
+    }
+
+    /// Move a damaged data file out of the way, into the quarantine directory
+    #[throws(Error)]
+    async fn quarantine(&self, file_path: &Path) {
+        let data = self.base.join(DATA);
+        let target = self
+            .base
+            .join(QUARANTINE)
+            .join(file_path.strip_prefix(&data).unwrap_or(file_path));
+        let repair_err = |e| Error::Repairing(file_path.to_owned(), e);
+        let size = fs::metadata(file_path).await.map_err(repair_err)?.len();
+        fs::create_dir_all(target.parent().unwrap())
+            .await
+            .map_err(repair_err)?;
+        fs::rename(file_path, &target).await.map_err(repair_err)?;
+        self.stats.lock().unwrap().data_removed(size);
+    }
+
+    /// Find every file in the data directory, along with its size
+    ///
+    /// Data files live in XX/YY/ under the data directory, anything else in
+    /// the data directory is ignored.
+    #[throws(io::Error)]
+    async fn scan_data(&self) -> Vec<(PathBuf, u64)> {
+        scan_objects(&self.base.join(DATA)).await?
+    }
+
+    #[throws(Error)]
+    pub async fn import<Claim, Name, Contents>(
+        &self,
+        name: Name,
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+        content: Contents,
+    ) where
+        Name: AsRef<OsStr>,
+        Claim: ResourceAllocation + 'static,
+        Contents: Stream<Item = ImportEvent> + Unpin,
+    {
+        let name = name.as_ref();
+        let _index = self.lock_index(name, true).await?;
+        let _storage = self.lock_storage(false).await?;
+        let root = self
+            .import_tree(&Directory::default(), provider, content)
+            .await?;
+
+        let root: InMemoryIndex = root.into();
+        self.save_index(name, &root).await?;
+        self.publish(name, root);
+        self.record_use(name).await?;
+        drop((_storage, _index));
+        self.evict_for(name).await;
+    }
+
+    /// Import a tarball, which may be compressed, to create an index
+    ///
+    /// This is a convenience wrapper around `import` using a decompressing
+    /// [`TarImportStream`](crate::util::TarImportStream).
+    #[throws(Error)]
+    pub async fn import_tar<Claim, Name, Reader>(
+        &self,
+        name: Name,
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+        tarball: Reader,
+    ) where
+        Name: AsRef<OsStr>,
+        Claim: ResourceAllocation + 'static,
+        Reader: AsyncRead + Unpin + Send + 'static,
+    {
+        let content = TarImportStream::new_decompressing(tarball)
+            .await
+            .map_err(|e| Error::ImportStreamError(e.into()))?;
+        self.import(name, provider, content.into_stream()).await?
+    }
+
+    /// Import content into the storage, returning the tree which describes it
+    ///
+    /// The content is imported over the directories of the base, which is
+    /// usually empty, so that it can add to directories which are already
+    /// there.  Only the content is returned, but hard links in the content
+    /// may be to files in the base.
+    #[throws(Error)]
+    async fn import_tree<Claim, Contents>(
+        &self,
+        base: &Directory,
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+        content: Contents,
+    ) -> Directory
+    where
+        Claim: ResourceAllocation + 'static,
+        Contents: Stream<Item = ImportEvent> + Unpin,
+    {
+        let mut root = base.skeleton();
+        let mut inserters: Inserters = FuturesUnordered::new();
+        let mut links = Vec::new();
+
+        match self
+            .import_(content, &mut root, &mut inserters, &mut links, provider)
+            .await
+        {
+            Err(e) => {
+                while inserters.next().await.is_some() {}
+                throw!(e);
+            }
+            Ok(_) => {
+                while let Some((parent_path, file_name, identity)) =
+                    inserters.next().await.transpose()?
+                {
+                    if let Some(parent_path) = parent_path {
+                        root.traverse_mut(&parent_path, false)?
+                            .insert_file(file_name, identity)?;
+                    } else {
+                        root.insert_file(file_name, identity)?;
+                    }
+                }
+                // Hard links can only be resolved once their targets are in
+                for (parent_path, file_name, target) in links {
+                    let identity = match root.file(&target) {
+                        Ok(identity) => identity.clone(),
+                        Err(Error::EntryNotFound(_)) => base.file(&target)?.clone(),
+                        Err(e) => throw!(e),
+                    };
+                    if let Some(parent_path) = parent_path {
+                        root.traverse_mut(&parent_path, false)?
+                            .insert_file(file_name, identity)?;
+                    } else {
+                        root.insert_file(file_name, identity)?;
+                    }
+                }
+            }
+        }
+        assert!(inserters.is_empty());
+        root
+    }
+
+    #[throws(Error)]
+    async fn import_<'a, Contents, Claim>(
+        &'a self,
+        mut content: Contents,
+        root: &mut Directory,
+        inserters: &mut Inserters<'a>,
+        links: &mut Vec<(Option<PathBuf>, OsString, PathBuf)>,
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+    ) where
+        Contents: Stream<Item = ImportEvent> + Unpin,
+        Claim: ResourceAllocation + 'static,
+    {
+        let mut event_ = content.next().await;
+        while let Some(event) = event_.take() {
+            // Before we do anything else, try and deal with any inserters
+            // who have completed
+            loop {
+                match futures::poll!(inserters.next()) {
+                    Poll::Ready(Some(Err(e))) => throw!(e),
+                    Poll::Ready(Some(Ok((parent_path, file_name, identity)))) => {
+                        if let Some(parent_path) = parent_path {
+                            root.traverse_mut(&parent_path, false)?
+                                .insert_file(file_name, identity)?;
+                        } else {
+                            root.insert_file(file_name, identity)?;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+            match event {
+                ImportEvent::Error(e) => throw!(Error::ImportStreamError(e)),
+                ImportEvent::FileData(_) => throw!(Error::UnexpectedFileData),
+                ImportEvent::Directory(d) => {
+                    if let Some(dirname) = d.file_name() {
+                        if let Some(parent) = d.parent() {
+                            root.traverse_mut(parent, false)?.mkdir(dirname)?;
+                        } else {
+                            root.mkdir(dirname)?;
+                        };
+                    }
+                }
+                ImportEvent::HardLink(parent_path, file_name, target) => {
+                    links.push((parent_path, file_name, target));
+                }
+                ImportEvent::Symlink(parent_path, file_name, target) => {
+                    if let Some(parent_path) = parent_path {
+                        root.traverse_mut(&parent_path, false)?
+                            .insert_symlink(file_name, target)?;
+                    } else {
+                        root.insert_symlink(file_name, target)?;
+                    }
+                }
+                ImportEvent::File(parent_path, file_name, size, executable) => {
+                    // We're trying to insert this file, so first we need
+                    // an allocation in order to make this possible
+                    let mut alloc =
+                        Self::claim(provider, size, &parent_path, &file_name, root, inserters)
+                            .await?;
+                    // We have an allocation, let's draw the next event
+                    // which must be file data
+                    match content.next().await {
+                        None => {
+                            alloc.release().await;
+                            throw!(Error::UnexpectedEndOfContent)
+                        }
+                        Some(ImportEvent::FileData(bytes)) => {
+                            // We have an allocation, we have the bytes, let's
+                            // spawn our future
+                            inserters.push(
+                                tokio::task::spawn(Self::import_file(
+                                    alloc,
+                                    self.base().to_owned(),
+                                    self.config.clone(),
+                                    self.stats.clone(),
+                                    parent_path,
+                                    file_name,
+                                    executable,
+                                    bytes,
+                                ))
+                                .map(|r| r.unwrap_or_else(|e| Err(Error::JoinError(e))))
+                                .boxed(),
+                            );
+                        }
+                        Some(ImportEvent::Error(e)) => {
+                            alloc.release().await;
+                            throw!(Error::ImportStreamError(e))
+                        }
+                        _ => {
+                            alloc.release().await;
+                            throw!(Error::ExpectedFileDataEvent)
+                        }
+                    }
+                }
+                ImportEvent::ChunkedFile(parent_path, file_name, size, executable) => {
+                    // Chunked files only ever need a chunk's worth of memory
+                    let claim_size = usize::try_from(size)
+                        .unwrap_or(IMPORT_CHUNK_SIZE)
+                        .min(IMPORT_CHUNK_SIZE);
+                    let alloc = Self::claim(
+                        provider,
+                        claim_size,
+                        &parent_path,
+                        &file_name,
+                        root,
+                        inserters,
+                    )
+                    .await?;
+                    // The chunks are handed over to a spooling task which
+                    // hashes them and writes them into the storage as they
+                    // arrive, with None marking the end of the file.  If it
+                    // fails it will stop receiving, and the error will be
+                    // reported when we collect the inserter.
+                    let (mut chunks, receiver) = mpsc::channel(1);
+                    let full_path = parent_path
+                        .as_deref()
+                        .unwrap_or_else(|| Path::new(""))
+                        .join(&file_name);
+                    inserters.push(
+                        tokio::task::spawn(Self::import_chunked_file(
+                            alloc,
+                            self.base().to_owned(),
+                            self.config.clone(),
+                            self.stats.clone(),
+                            parent_path,
+                            file_name,
+                            executable,
+                            size,
+                            receiver,
+                        ))
+                        .map(|r| r.unwrap_or_else(|e| Err(Error::JoinError(e))))
+                        .boxed(),
+                    );
+                    loop {
+                        match content.next().await {
+                            None => throw!(Error::UnexpectedEndOfContent),
+                            Some(ImportEvent::FileData(bytes)) => {
+                                if bytes.len() > IMPORT_CHUNK_SIZE {
+                                    throw!(Error::FileChunkTooLarge(full_path, bytes.len()));
+                                }
+                                chunks.send(Some(bytes)).await.unwrap_or(());
+                            }
+                            Some(ImportEvent::EndOfFile) => {
+                                chunks.send(None).await.unwrap_or(());
+                                break;
+                            }
+                            Some(ImportEvent::Error(e)) => throw!(Error::ImportStreamError(e)),
+                            _ => throw!(Error::ExpectedFileDataEvent),
+                        }
+                    }
+                }
+                ImportEvent::EndOfFile => throw!(Error::UnexpectedFileData),
+            }
+            event_ = content.next().await;
+        }
+    }
+
+    /// Claim resources for importing a file, inserting completed files into
+    /// the index while we wait for the resource provider
+    #[throws(Error)]
+    async fn claim<Claim>(
+        provider: &mut dyn ResourceProvider<ResourceClaim = Claim>,
+        size: usize,
+        parent_path: &Option<PathBuf>,
+        file_name: &OsStr,
+        root: &mut Directory,
+        inserters: &mut Inserters<'_>,
+    ) -> Claim
+    where
+        Claim: ResourceAllocation + 'static,
+    {
+        loop {
+            match provider.claim(size).await {
+                ResourceClaimResult::Impossible => throw!(Error::ImpossibleFileClaim(
+                    parent_path
+                        .as_deref()
+                        .unwrap_or_else(|| Path::new(""))
+                        .join(file_name),
+                    size
+                )),
+                ResourceClaimResult::Busy => {
+                    if let Some((parent_path, file_name, identity)) =
+                        inserters.next().await.transpose()?
+                    {
+                        if let Some(parent_path) = parent_path {
+                            root.traverse_mut(&parent_path, false)?
+                                .insert_file(file_name, identity)?;
+                        } else {
+                            root.insert_file(file_name, identity)?;
+                        }
+                    }
+                }
+                ResourceClaimResult::Ok(claim) => break claim,
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[throws(Error)]
+    async fn import_chunked_file(
+        mut allocation: impl ResourceAllocation,
+        base_path: PathBuf,
+        config: StorageConfig,
+        stats: Arc<Mutex<StatsCache>>,
+        parent_path: Option<PathBuf>,
+        file_name: OsString,
+        executable: bool,
+        size: u64,
+        chunks: mpsc::Receiver<Option<Bytes>>,
+    ) -> (Option<PathBuf>, OsString, StorageIdentifier) {
+        let full_path = parent_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(""))
+            .join(&file_name);
+        let result = Self::spool_chunks(
+            &base_path, &config, &stats, &full_path, executable, size, None, chunks,
+        )
+        .await;
+        allocation.release().await;
+        (parent_path, file_name, result?)
+    }
+
+    /// Spool the chunks of a file into a temporary file in the storage,
+    /// hashing it as we go, and then move it into place
+    ///
+    /// If the hash of the content is known in advance, content which does
+    /// not match it is discarded rather than stored.
+    #[allow(clippy::too_many_arguments)]
+    #[throws(Error)]
+    async fn spool_chunks(
+        base_path: &Path,
+        config: &StorageConfig,
+        stats: &Mutex<StatsCache>,
+        full_path: &Path,
+        executable: bool,
+        size: u64,
+        expected_hash: Option<&str>,
+        mut chunks: mpsc::Receiver<Option<Bytes>>,
+    ) -> StorageIdentifier {
+        use sha2::{Digest, Sha256};
+        let (temp_file, mut fh) = loop {
+            let temp_file = base_path.join(DATA).join(format!(
+                "incoming-{}-{}.tmp",
+                std::process::id(),
+                SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_file)
+                .await
+            {
+                Ok(fh) => break (temp_file, fh),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => throw!(Error::IOErrorAddingToStorage(temp_file, e)),
+            }
+        };
+        let mut hasher = Sha256::new();
+        let mut total: u64 = 0;
+        let spooled: Result<bool, io::Error> = async {
+            while let Some(chunk) = chunks.recv().await {
+                match chunk {
+                    Some(chunk) => {
+                        hasher.input(&chunk);
+                        total += chunk.len() as u64;
+                        fh.write_all(&chunk).await?;
+                    }
+                    None => {
+                        fh.flush().await?;
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        }
+        .await;
+        drop(fh);
+        let identity = match spooled {
+            Err(e) => Err(Error::IOErrorAddingToStorage(temp_file.clone(), e)),
+            // The import was abandoned part way through this file
+            Ok(false) => Err(Error::UnexpectedEndOfContent),
+            Ok(true) => match usize::try_from(total) {
+                Ok(len) if total == size => {
+                    let hash = format!("{:x}", hasher.result());
+                    match expected_hash {
+                        Some(expected) if expected != hash => Err(Error::ContentMismatch(
+                            full_path.to_owned(),
+                            expected.into(),
+                        )),
+                        _ => Ok(StorageIdentifier::new(hash, len, executable)),
+                    }
+                }
+                _ => Err(Error::FileSizeMismatch(full_path.to_owned(), size, total)),
+            },
+        };
+        let identity = match identity {
+            Ok(identity) => identity,
+            Err(e) => {
+                fs::remove_file(&temp_file).await.unwrap_or(());
+                throw!(e);
+            }
+        };
+        let placed = async {
+            let entry_path = identity.filename(base_path);
+            let add_err = |e| Error::IOErrorAddingToStorage(entry_path.clone(), e);
+            if blob_present(base_path, &identity).await.map_err(add_err)? {
+                return Ok(());
+            }
+            let chunking = config.chunking.filter(|c| c.chunks(identity.size));
+            if chunking.is_none() && config.compression.is_none() {
+                // The spooled file can simply be moved into place
+                fs::create_dir_all(entry_path.parent().unwrap())
+                    .await
+                    .map_err(add_err)?;
+                set_read_only(&temp_file, identity.executable)
+                    .await
+                    .map_err(add_err)?;
+                fs::rename(&temp_file, &entry_path).await.map_err(add_err)?;
+                stats.lock().unwrap().data_added(total);
+                return Ok(());
+            }
+            let fh = fs::File::open(&temp_file)
+                .await
+                .map_err(|e| Error::IOErrorAddingToStorage(temp_file.clone(), e))?;
+            match chunking {
+                Some(chunking) => {
+                    let compression = config.compression;
+                    write_chunked_blob(base_path, stats, &identity, &chunking, compression, fh)
+                        .await
+                }
+                None => write_blob(base_path, stats, &identity, config.compression, fh).await,
+            }
+        }
+        .await;
+        fs::remove_file(&temp_file).await.unwrap_or(());
+        placed?;
+        identity
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[throws(Error)]
+    async fn import_file(
+        mut allocation: impl ResourceAllocation,
+        base_path: PathBuf,
+        config: StorageConfig,
+        stats: Arc<Mutex<StatsCache>>,
+        parent_path: Option<PathBuf>,
+        file_name: OsString,
+        executable: bool,
+        contents: Bytes,
+    ) -> (Option<PathBuf>, OsString, StorageIdentifier) {
+        use bytes::Buf;
+        // Rough approach is as follows...
+        // First we compute the identifier for the input data and decide
+        // if we already have it.
+        let size = contents.len();
+        let hash = tokio::task::block_in_place(|| {
+            use sha2::{Digest, Sha256};
+            let mut hasher = Sha256::new();
+            hasher.input(contents.bytes());
+            let result = hasher.result();
+            format!("{:x}", result)
+        });
+        let identity = StorageIdentifier::new(hash, size, executable);
+        // Next we need to see if we need to insert it into the store
+        let present = blob_present(&base_path, &identity)
+            .await
+            .map_err(|e| Error::IOErrorAddingToStorage(identity.filename(&base_path), e))?;
+        if !present {
+            store_blob(&base_path, &config, &stats, &identity, contents.bytes()).await?;
+        }
+
+        // Clean up our memory usage
+        drop(contents);
+        allocation.release().await;
+        (parent_path, file_name, identity)
+    }
+}
+
+/// Directories are stored in the trees directory, laid out as data is
+fn tree_path(base: &Path, digest: &str) -> PathBuf {
+    hashed_path(base.join(TREES), digest)
+}
+
+/// Where the action cache entry for an action's hash is kept
+fn action_path(base: &Path, hash: &str) -> PathBuf {
+    hashed_path(base.join(ACTIONS), hash)
+}
+
+/// Spread files named by hash over directories as the data files are
+fn hashed_path(mut path: PathBuf, hash: &str) -> PathBuf {
+    path.push(&hash[0..2]);
+    path.push(&hash[2..4]);
+    path.push(&hash[4..]);
+    path
+}
+
+/// The SHA-256 of no content, which is always treated as present, as REAPI
+/// requires, whether or not any file holds it
+pub(crate) const EMPTY_HASH: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Whether a string is a hash as used to name content
+pub(crate) fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Encode a directory as it is stored in the trees directory
+fn encode_node(node: &TreeNode) -> Result<Vec<u8>, bincode::Error> {
+    bincode::DefaultOptions::new().serialize(node)
+}
+
+/// Read a directory from the trees directory
+#[throws(Error)]
+fn read_node(base: &Path, digest: &str, max_size: Option<u64>) -> TreeNode {
+    if !valid_hash(digest) {
+        throw!(Error::CorruptTree(digest.into()));
+    }
+    let node_path = tree_path(base, digest);
+    let file = match std::fs::File::open(&node_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => throw!(Error::MissingTree(digest.into())),
+        Err(e) => throw!(Error::Preparing(e)),
+    };
+    let len = file.metadata().map_err(Error::Preparing)?.len();
+    if matches!(max_size, Some(max) if len > max) {
+        throw!(Error::IndexTooLarge(node_path, len));
+    }
+    bincode::DefaultOptions::new()
+        .deserialize_from(std::io::BufReader::new(file))
+        .map_err(|e| Error::DecodingIndex(node_path, e))?
+}
+
+/// Read an index file, which may be in the binary or the legacy json5 format,
+/// returning the index and whether or not it was in an older format
+///
+/// Binary indices are decoded as they are read, so they need not fit in
+/// memory twice over.
+#[throws(Error)]
+async fn read_index(base: &Path, path: &Path, max_size: Option<u64>) -> (Directory, bool) {
+    let base = base.to_owned();
+    let path = path.to_owned();
+    let read = tokio::task::spawn_blocking(move || -> Result<_, Error> {
+        use std::convert::TryInto;
+        use std::io::{BufReader, Read, Seek, SeekFrom};
+        let file = std::fs::File::open(&path).map_err(Error::Preparing)?;
+        let len = file.metadata().map_err(Error::Preparing)?.len();
+        let mut file = BufReader::new(file);
+        let mut header = [0; INDEX_MAGIC.len() + 4];
+        let binary = match file.read_exact(&mut header) {
+            Ok(()) => header.starts_with(INDEX_MAGIC),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(Error::Preparing(e)),
+        };
+        if binary {
+            if matches!(max_size, Some(max) if len > max) {
+                return Err(Error::IndexTooLarge(path, len));
+            }
+            let version = u32::from_le_bytes(header[INDEX_MAGIC.len()..].try_into().unwrap());
+            match version {
+                FLAT_INDEX_VERSION => {
+                    let dir = bincode::DefaultOptions::new()
+                        .deserialize_from(file)
+                        .map_err(|e| Error::DecodingIndex(path, e))?;
+                    Ok((dir, true))
+                }
+                TREE_INDEX_VERSION => {
+                    let mut digest = String::new();
+                    file.read_to_string(&mut digest).map_err(Error::Preparing)?;
+                    let mut load = |digest: &str| read_node(&base, digest, max_size);
+                    Ok((Directory::from_tree(&digest, &mut load)?, false))
+                }
+                _ => Err(Error::UnsupportedIndexVersion(path, version)),
+            }
+        } else {
+            if len > MAX_METADATA_SIZE {
+                return Err(Error::IndexTooLarge(path, len));
+            }
+            let mut body = String::new();
+            file.seek(SeekFrom::Start(0)).map_err(Error::Preparing)?;
+            file.read_to_string(&mut body).map_err(Error::Preparing)?;
+            let dir = Directory::try_from(body.as_ref()).map_err(Error::ParsingIndex)?;
+            Ok((dir, true))
+        }
+    });
+    read.await??
+}
+
+/// Read the digest of the root directory of an index, if the index is stored
+/// in the current format
+#[throws(io::Error)]
+async fn read_index_root(path: &Path) -> Option<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut header = Vec::new();
+    (&mut file)
+        .take((INDEX_MAGIC.len() + 4 + 64) as u64)
+        .read_to_end(&mut header)
+        .await?;
+    let (magic, rest) = header.split_at(INDEX_MAGIC.len().min(header.len()));
+    match rest.split_at(4.min(rest.len())) {
+        (version, digest)
+            if magic == INDEX_MAGIC && version == TREE_INDEX_VERSION.to_le_bytes() =>
+        {
+            String::from_utf8(digest.to_vec()).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Find every file in XX/YY/ under a directory, along with its size
+#[throws(io::Error)]
+async fn scan_objects(dir: &Path) -> Vec<(PathBuf, u64)> {
+    let mut ret = Vec::new();
+    let mut outer = fs::read_dir(dir).await?;
+    while let Some(outer_entry) = outer.next_entry().await? {
+        if !outer_entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut inner = fs::read_dir(outer_entry.path()).await?;
+        while let Some(inner_entry) = inner.next_entry().await? {
+            if !inner_entry.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = fs::read_dir(inner_entry.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let meta = file.metadata().await?;
+                if meta.is_file() {
+                    ret.push((file.path(), meta.len()));
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// How many bytes the files found by [`scan_objects`] hold, not counting
+/// temporary files
+fn data_size(files: &[(PathBuf, u64)]) -> u64 {
+    files
+        .iter()
+        .filter(|(file_path, _)| file_path.extension() != Some(OsStr::new("tmp")))
+        .map(|(_, size)| size)
+        .sum()
+}
+
+/// Set the modification time of a file to now, to record that it was used,
+/// creating the file if asked to
+#[throws(io::Error)]
+async fn mark_used(path: &Path, create: bool) {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        if create {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+        }
+        filetime::set_file_mtime(&path, filetime::FileTime::now())
+    })
+    .await??
+}
+
+/// Remove a file found by [`scan_objects`], tidying up the directories it
+/// was in if they are now empty
+#[throws(io::Error)]
+async fn remove_object(path: &Path) {
+    fs::remove_file(path).await?;
+    // Failure here simply means the directories are still in use
+    let inner_path = path.parent().unwrap();
+    if fs::remove_dir(inner_path).await.is_ok() {
+        fs::remove_dir(inner_path.parent().unwrap())
+            .await
+            .unwrap_or(());
+    }
+}
+
+/// A unique temporary name to write a file under before renaming it into place
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    temp.into()
+}
+
+/// Data can be stored raw, compressed, or as a list of chunks.  This is the path
+/// of the chunk list for an identity.
+fn chunk_list_path(base: &Path, identity: &StorageIdentifier) -> PathBuf {
+    identity.filename(base).with_extension(CHUNKS)
+}
+
+/// Whether or not the data for an identity is in the storage in any form
+#[throws(io::Error)]
+async fn blob_present(base: &Path, identity: &StorageIdentifier) -> bool {
+    for path in &[
+        identity.filename(base),
+        compressed_path(base, identity),
+        chunk_list_path(base, identity),
+    ] {
+        match fs::metadata(path).await {
+            Ok(_) => return true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => throw!(e),
+        }
+    }
+    false
+}
+
+/// Write a file into place atomically, via a temporary file
+#[throws(io::Error)]
+async fn write_atomically<R>(path: &Path, mut content: R) -> u64
+where
+    R: AsyncRead + Unpin,
+{
+    fs::create_dir_all(path.parent().unwrap()).await?;
+    let temp_file = temp_path(path);
+    let written: io::Result<_> = async {
+        let mut fh = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_file)
+            .await?;
+        let len = io::copy(&mut content, &mut fh).await?;
+        // Complete any pending background IO
+        fh.flush().await?;
+        // Having flushed we can drop the fh to know it's closed
+        drop(fh);
+        fs::rename(&temp_file, path).await?;
+        Ok(len)
+    }
+    .await;
+    match written {
+        Ok(len) => len,
+        Err(e) => {
+            fs::remove_file(&temp_file).await.unwrap_or(());
+            throw!(e);
+        }
+    }
+}
+
+/// Compressed data is stored alongside where the raw data would be
+fn compressed_path(base: &Path, identity: &StorageIdentifier) -> PathBuf {
+    identity.filename(base).with_extension(COMPRESSED)
+}
+
+fn compressor<R>(content: R, level: u32) -> ZstdEncoder<io::BufReader<R>>
+where
+    R: AsyncRead + Unpin,
+{
+    ZstdEncoder::with_quality(io::BufReader::new(content), Level::Precise(level))
+}
+
+/// Write the data for an identity into the storage as a single file
+#[throws(Error)]
+async fn write_blob<R>(
+    base: &Path,
+    stats: &Mutex<StatsCache>,
+    identity: &StorageIdentifier,
+    compression: Option<u32>,
+    content: R,
+) where
+    R: AsyncRead + Unpin,
+{
+    let written = match compression {
+        Some(level) => {
+            let entry_path = compressed_path(base, identity);
+            let written = write_atomically(&entry_path, compressor(content, level)).await;
+            written.map_err(|e| Error::IOErrorAddingToStorage(entry_path, e))
+        }
+        None => {
+            let entry_path = identity.filename(base);
+            let written = match write_atomically(&entry_path, content).await {
+                Ok(len) => set_read_only(&entry_path, identity.executable)
+                    .await
+                    .map(|()| len),
+                Err(e) => Err(e),
+            };
+            written.map_err(|e| Error::IOErrorAddingToStorage(entry_path, e))
+        }
+    };
+    stats.lock().unwrap().data_added(written?);
+}
+
+/// Write the data for an identity into the storage in whichever form the
+/// configuration calls for
+#[throws(Error)]
+async fn store_blob<R>(
+    base: &Path,
+    config: &StorageConfig,
+    stats: &Mutex<StatsCache>,
+    identity: &StorageIdentifier,
+    content: R,
+) where
+    R: AsyncRead + Unpin,
+{
+    let compression = config.compression;
+    match config.chunking {
+        Some(chunking) if chunking.chunks(identity.size) => {
+            write_chunked_blob(base, stats, identity, &chunking, compression, content).await?
+        }
+        _ => write_blob(base, stats, identity, compression, content).await?,
+    }
+}
+
+/// Read the data for an identity stored as a single file
+#[throws(io::Error)]
+async fn read_blob(base: &Path, identity: &StorageIdentifier) -> Bytes {
+    match fs::read(identity.filename(base)).await {
+        Ok(content) => return content.into(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => throw!(e),
+    }
+    let file = fs::File::open(compressed_path(base, identity)).await?;
+    let mut content = Vec::with_capacity(identity.size);
+    ZstdDecoder::new(io::BufReader::new(file))
+        .read_to_end(&mut content)
+        .await?;
+    content.into()
+}
+
+/// Write the data for an identity into the storage as a list of content
+/// defined chunks, each of which is stored as data in its own right
+#[throws(Error)]
+async fn write_chunked_blob<R>(
+    base: &Path,
+    stats: &Mutex<StatsCache>,
+    identity: &StorageIdentifier,
+    chunking: &ChunkingConfig,
+    compression: Option<u32>,
+    mut content: R,
+) where
+    R: AsyncRead + Unpin,
+{
+    use sha2::{Digest, Sha256};
+    let list_path = chunk_list_path(base, identity);
+    let chunker = chunking.chunker();
+    let mut buffer = Vec::with_capacity(chunker.max_size());
+    let mut list = String::new();
+    let mut eof = false;
+    while !eof || !buffer.is_empty() {
+        // Keep at least a maximal chunk's worth of data in the buffer
+        while !eof && buffer.len() < chunker.max_size() {
+            let len = buffer.len();
+            buffer.resize(chunker.max_size(), 0);
+            let got = content
+                .read(&mut buffer[len..])
+                .await
+                .map_err(|e| Error::IOErrorAddingToStorage(list_path.clone(), e))?;
+            buffer.truncate(len + got);
+            eof = got == 0;
+        }
+        let len = chunker.boundary(&buffer).unwrap_or(buffer.len());
+        let chunk = &buffer[..len];
+        let chunk_identity =
+            StorageIdentifier::new(format!("{:x}", Sha256::digest(chunk)), len, false);
+        let present = blob_present(base, &chunk_identity)
+            .await
+            .map_err(|e| Error::IOErrorAddingToStorage(chunk_identity.filename(base), e))?;
+        if !present {
+            write_blob(base, stats, &chunk_identity, compression, chunk).await?;
+        }
+        list.push_str(&format!(
+            "{} {}\n",
+            chunk_identity.hash, chunk_identity.size
+        ));
+        buffer.drain(..len);
+    }
+    let written = write_atomically(&list_path, list.as_bytes())
+        .await
+        .map_err(|e| Error::IOErrorAddingToStorage(list_path, e))?;
+    stats.lock().unwrap().data_added(written);
+}
+
+/// A reader over the concatenated content of a list of chunks
+///
+/// Chunks are read one at a time, as the reader reaches them.
+fn chunk_reader(base: &Path, chunks: Vec<StorageIdentifier>) -> ContentReader {
+    let base = base.to_owned();
+    let chunks = futures::stream::iter(chunks).then(move |chunk| {
+        let base = base.clone();
+        async move { read_blob(&base, &chunk).await }
+    });
+    Box::new(io::stream_reader(Box::pin(chunks)))
+}
+
+/// Read the list of chunks which make up some data
+#[throws(io::Error)]
+async fn read_chunk_list(path: &Path) -> Vec<StorageIdentifier> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "malformed chunk list");
+    let mut ret = Vec::new();
+    for line in fs::read_to_string(path).await?.lines() {
+        let mut parts = line.split(' ');
+        match (parts.next(), parts.next().map(str::parse), parts.next()) {
+            (Some(hash), Some(Ok(size)), None) if valid_hash(hash) => {
+                ret.push(StorageIdentifier::new(hash.into(), size, false))
+            }
+            _ => throw!(bad()),
+        }
+    }
+    ret
+}
+
+/// Work out the identity of a data file from its path, if it is a valid
+/// name for a data file in any of the forms data is stored in
+fn parse_data_path(base: &Path, path: &Path) -> Option<StorageIdentifier> {
+    let inner = path.parent()?;
+    let outer = inner.parent()?;
+    let stem = path.file_stem()?.to_str()?;
+    let dash = stem.rfind('-')?;
+    let (rest, size) = (&stem[..dash], &stem[dash + 1..]);
+    let executable = size.ends_with('x');
+    let size = size.trim_end_matches('x');
+    let hash = format!(
+        "{}{}{}",
+        outer.file_name()?.to_str()?,
+        inner.file_name()?.to_str()?,
+        rest
+    );
+    if !valid_hash(&hash) {
+        return None;
+    }
+    let identity = StorageIdentifier::new(hash, size.parse().ok()?, executable);
+    // Anything which isn't exactly how we would name the data is not ours
+    if identity.filename(base) == path.with_extension("") {
+        Some(identity)
+    } else {
+        None
+    }
+}
+
+/// Whether or not content has the hash and size of an identity
+///
+/// Errors while reading the content, such as from failing to decompress
+/// it, mean that it does not.
+async fn content_matches<R>(identity: &StorageIdentifier, mut content: R) -> bool
+where
+    R: AsyncRead + Unpin,
+{
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut total: u64 = 0;
+    loop {
+        match content.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(len) => {
+                hasher.input(&buffer[..len]);
+                total += len as u64;
+            }
+            Err(_) => return false,
+        }
+    }
+    total == identity.size as u64 && format!("{:x}", hasher.result()) == identity.hash
+}
+
+#[cfg(windows)]
+pub(crate) fn os_str_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().into_owned().into_bytes()
+}
+#[cfg(not(windows))]
+pub(crate) fn os_str_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+#[cfg(target_os = "linux")]
+#[throws(io::Error)]
+fn reflink(source: &Path, target: &Path) {
+    use std::os::unix::io::AsRawFd;
+    let input = std::fs::File::open(source)?;
+    let output = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)?;
+    // Safety: both file descriptors are valid for the duration of the call
+    if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        drop(output);
+        std::fs::remove_file(target).unwrap_or(());
+        throw!(e);
+    }
+}
+#[cfg(not(target_os = "linux"))]
+#[throws(io::Error)]
+fn reflink(_source: &Path, _target: &Path) {
+    throw!(io::Error::new(
+        io::ErrorKind::Other,
+        "reflinks are not supported on this platform"
+    ));
+}
+
+#[cfg(windows)]
+#[throws(io::Error)]
+async fn symlink(_link: &OsStr, _target: &Path) {
+    throw!(io::Error::new(
+        io::ErrorKind::Other,
+        "symbolic links are not supported on this platform"
+    ));
+}
+#[cfg(not(windows))]
+#[throws(io::Error)]
+async fn symlink(link: &OsStr, target: &Path) {
+    fs::os::unix::symlink(link, target).await?;
+}
+
+#[cfg(windows)]
+#[throws(io::Error)]
+async fn set_executable(_path: &Path, _executable: bool) {}
+#[cfg(not(windows))]
+#[throws(io::Error)]
+async fn set_executable(path: &Path, executable: bool) {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o755 } else { 0o644 };
+    fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+}
+
+/// Raw data files are read-only, so that files hard linked to them cannot be
+/// used to change them by accident, and executable if their content is
+#[cfg(windows)]
+#[throws(io::Error)]
+async fn set_read_only(_path: &Path, _executable: bool) {}
+#[cfg(not(windows))]
+#[throws(io::Error)]
+async fn set_read_only(path: &Path, executable: bool) {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = if executable { 0o555 } else { 0o444 };
+    fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn file_events(files: &[(&str, &str)]) -> Vec<ImportEvent> {
+        let mut events = Vec::new();
+        for (name, content) in files {
+            events.push(ImportEvent::File(
+                None,
+                (*name).into(),
+                content.len(),
+                false,
+            ));
+            events.push(ImportEvent::FileData(Bytes::copy_from_slice(
+                content.as_bytes(),
+            )));
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn create_twice() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        drop(ss);
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage a second time");
+        drop(ss);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn remove_and_collect() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        ss.import(
+            "one",
+            &mut provider,
+            stream::iter(file_events(&[("a", "shared"), ("b", "only one")])),
+        )
+        .await
+        .unwrap();
+        ss.import(
+            "two",
+            &mut provider,
+            stream::iter(file_events(&[("a", "shared"), ("c", "two!")])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ss.collect_garbage().await.unwrap(),
+            GarbageReport::default()
+        );
+        ss.remove_index("one").await.unwrap();
+        assert!(ss.indices().all(|n| n != "one"));
+        assert!(!td.path().join(INDICES).join("one").exists());
+        let report = ss.collect_garbage().await.unwrap();
+        assert_eq!(report.blobs_freed, 1);
+        assert_eq!(report.bytes_freed, 8);
+        assert!(matches!(
+            ss.remove_index("one").await,
+            Err(Error::IndexNotFound(_))
+        ));
+        ss.remove_index("two").await.unwrap();
+        let report = ss.collect_garbage().await.unwrap();
+        assert_eq!(report.blobs_freed, 2);
+        assert_eq!(report.bytes_freed, 10);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn merge_indices() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        ss.import(
+            "one",
+            &mut provider,
+            stream::iter(file_events(&[("a", "shared"), ("b", "only one")])),
+        )
+        .await
+        .unwrap();
+        ss.import(
+            "two",
+            &mut provider,
+            stream::iter(file_events(&[("a", "shared"), ("b", "two!")])),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            ss.merge("three", &["one", "two"], MergePolicy::default())
+                .await,
+            Err(Error::FileEntryExistsAsFile(_))
+        ));
+        assert!(ss.indices().all(|n| n != "three"));
+        let used = ss.last_used("one").await.unwrap();
+        ss.merge("three", &["one", "two"], MergePolicy::LastWins)
+            .await
+            .unwrap();
+        assert!(ss.last_used("one").await.unwrap() > used);
+        assert!(matches!(
+            ss.merge("three", &["one"], MergePolicy::default()).await,
+            Err(Error::IndexExists(_))
+        ));
+        assert!(matches!(
+            ss.merge("one", &["two", "one"], MergePolicy::default())
+                .await,
+            Err(Error::IndexExists(_))
+        ));
+        assert!(td.path().join(INDICES).join("three").exists());
+        assert_eq!(ss.indices().count(), 3);
+        let three = &ss.index(OsStr::new("three")).unwrap().dir;
+        assert!(three.iter().all(|(name, entry)| match entry {
+            DirectoryEntry::File(f) => name != "b" || f.size == 4,
+            _ => false,
+        }));
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test(threaded_scheduler)]
+    async fn export_copy_and_hardlink() {
+        use std::os::unix::fs::MetadataExt;
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(td.path().join("storage"))
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        ss.import(
+            "one",
+            &mut provider,
+            stream::iter(vec![
+                ImportEvent::Directory("bin".into()),
+                ImportEvent::File(Some("bin".into()), "prog".into(), 4, true),
+                ImportEvent::FileData(Bytes::from_static(b"prog")),
+                ImportEvent::File(None, "README".into(), 6, false),
+                ImportEvent::FileData(Bytes::from_static(b"readme")),
+                ImportEvent::Symlink(Some("bin".into()), "link".into(), "prog".into()),
+            ]),
+        )
+        .await
+        .unwrap();
+        for (mode, dir) in &[(ExportMode::Copy, "copy"), (ExportMode::Hardlink, "link")] {
+            let target = td.path().join(dir);
+            ss.export_to_dir("one", &target, *mode).await.unwrap();
+            let prog = target.join("bin").join("prog");
+            assert_eq!(std::fs::read(&prog).unwrap(), b"prog");
+            let meta = std::fs::metadata(&prog).unwrap();
+            assert_eq!(meta.mode() & 0o111, 0o111);
+            assert_eq!(meta.nlink(), if *mode == ExportMode::Copy { 1 } else { 2 });
+            let writable = if *mode == ExportMode::Copy { 0o200 } else { 0 };
+            assert_eq!(meta.mode() & 0o222, writable);
+            let meta = std::fs::metadata(target.join("README")).unwrap();
+            assert_eq!(meta.mode() & 0o111, 0);
+            assert_eq!(meta.mode() & 0o222, writable);
+            assert_eq!(
+                std::fs::read_link(target.join("bin").join("link")).unwrap(),
+                PathBuf::from("prog")
+            );
+        }
+        assert!(matches!(
+            ss.export_to_dir("one", td.path().join("copy"), ExportMode::Copy)
+                .await,
+            Err(Error::Exporting(_, _))
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn export_tar_roundtrip() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let long_dir = "d".repeat(120);
+        let mut events = vec![
+            ImportEvent::Directory("bin".into()),
+            ImportEvent::File(Some("bin".into()), "prog".into(), 4, true),
+            ImportEvent::FileData(Bytes::from_static(b"prog")),
+            ImportEvent::Directory(long_dir.clone().into()),
+            ImportEvent::File(Some(long_dir.clone().into()), "inner".into(), 5, false),
+            ImportEvent::FileData(Bytes::from_static(b"inner")),
+            ImportEvent::Symlink(None, "link".into(), format!("{}/inner", long_dir).into()),
+        ];
+        events.extend(file_events(&[("z", "last"), ("a", "first"), ("m", "")]));
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let tarball = ss.export_tar("one", Vec::new()).await.unwrap();
+        assert_eq!(tarball.len() % 512, 0);
+        assert_eq!(tarball, ss.export_tar("one", Vec::new()).await.unwrap());
+
+        let mut archive = tar::Archive::new(&tarball[..]);
+        let paths: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().into_owned())
+            .collect();
+        let mut sorted = paths.clone();
+        sorted.sort();
+        assert_eq!(paths, sorted);
+        assert_eq!(paths.len(), 8);
+
+        ss.import(
+            "two",
+            &mut provider,
+            crate::util::TarImportStream::new(&tarball[..]).into_stream(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            ss.index(OsStr::new("one")).unwrap().dir,
+            ss.index(OsStr::new("two")).unwrap().dir
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn open_files() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let mut events = vec![
+            ImportEvent::Directory("etc".into()),
+            ImportEvent::File(Some("etc".into()), "config".into(), 6, false),
+            ImportEvent::FileData(Bytes::from_static(b"config")),
+        ];
+        events.extend(file_events(&[("manifest", "manifest")]));
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let mut content = String::new();
+        ss.open("one", "manifest")
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        assert_eq!(content, "manifest");
+        assert_eq!(
+            ss.read_to_bytes("one", "./etc/config").await.unwrap(),
+            Bytes::from_static(b"config")
+        );
+        assert!(matches!(
+            ss.open("one", "etc").await,
+            Err(Error::EntryIsDirectory(_))
+        ));
+        assert!(matches!(
+            ss.open("one", "").await,
+            Err(Error::EntryIsDirectory(_))
+        ));
+        assert!(matches!(
+            ss.open("one", "etc/missing").await,
+            Err(Error::EntryNotFound(_))
+        ));
+        assert!(matches!(
+            ss.open("one", "manifest/inner").await,
+            Err(Error::EntryNotDirectory(_))
+        ));
+        assert!(matches!(
+            ss.open("two", "manifest").await,
+            Err(Error::IndexNotFound(_))
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn bad_chunked_files() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let short = vec![
+            ImportEvent::ChunkedFile(None, "short".into(), 10, false),
+            ImportEvent::FileData(Bytes::from_static(b"12345")),
+            ImportEvent::EndOfFile,
+        ];
+        assert!(matches!(
+            ss.import("one", &mut provider, stream::iter(short)).await,
+            Err(Error::FileSizeMismatch(_, 10, 5))
+        ));
+        let huge = vec![
+            ImportEvent::ChunkedFile(None, "huge".into(), 1 << 40, false),
+            ImportEvent::FileData(vec![0; IMPORT_CHUNK_SIZE + 1].into()),
+            ImportEvent::EndOfFile,
+        ];
+        assert!(matches!(
+            ss.import("one", &mut provider, stream::iter(huge)).await,
+            Err(Error::FileChunkTooLarge(_, _))
+        ));
+        let unterminated = vec![
+            ImportEvent::ChunkedFile(None, "unterminated".into(), 5, false),
+            ImportEvent::FileData(Bytes::from_static(b"12345")),
+        ];
+        assert!(matches!(
+            ss.import("one", &mut provider, stream::iter(unterminated))
+                .await,
+            Err(Error::UnexpectedEndOfContent)
+        ));
+        assert_eq!(ss.indices().count(), 0);
+        // Nothing should be left behind from the failed imports
+        let mut data = fs::read_dir(td.path().join(DATA)).await.unwrap();
+        assert!(data.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn chunked_storage() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let config = StorageConfig {
+            chunking: Some(ChunkingConfig {
+                min_size: 1024,
+                avg_size: 4096,
+                max_size: 16384,
+            }),
+            ..Default::default()
+        };
+        let ss = SharedStorage::new_with_config(&td, config)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let mut state = 1u64;
+        let data: Vec<u8> = (0..128 * 1024)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let mut edited = data.clone();
+        edited[64 * 1024] ^= 0xff;
+
+        let one = vec![
+            ImportEvent::File(None, "big".into(), data.len(), false),
+            ImportEvent::FileData(data.clone().into()),
+        ];
+        ss.import("one", &mut provider, stream::iter(one))
+            .await
+            .unwrap();
+        // The edited copy arrives in pieces, as from a large file on disk
+        let mut two = vec![ImportEvent::ChunkedFile(
+            None,
+            "big".into(),
+            edited.len() as u64,
+            true,
+        )];
+        two.extend(
+            edited
+                .chunks(10000)
+                .map(|c| ImportEvent::FileData(Bytes::copy_from_slice(c))),
+        );
+        two.push(ImportEvent::EndOfFile);
+        ss.import("two", &mut provider, stream::iter(two))
+            .await
+            .unwrap();
+
+        assert_eq!(ss.read_to_bytes("one", "big").await.unwrap(), data);
+        assert_eq!(ss.read_to_bytes("two", "big").await.unwrap(), edited);
+        let identity = ss
+            .index(OsStr::new("one"))
+            .unwrap()
+            .dir
+            .file("big")
+            .unwrap()
+            .clone();
+        assert!(!identity.filename(&ss.base).exists());
+        // Chunk lists naming anything but hashes are malformed
+        let list_path = td.path().join("bad.chunks");
+        std::fs::write(&list_path, "../../../outside/data 5\n").unwrap();
+        let err = read_chunk_list(&list_path).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let stored: u64 = ss.scan_data().await.unwrap().iter().map(|f| f.1).sum();
+        assert!(stored < (data.len() + data.len() / 2) as u64);
+
+        // Hard links are impossible for chunked content, so it is copied
+        let target = td.path().join("export");
+        ss.export_to_dir("two", &target, ExportMode::Hardlink)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(target.join("big")).unwrap(), edited);
+
+        ss.remove_index("one").await.unwrap();
+        let report = ss.collect_garbage().await.unwrap();
+        assert!(report.blobs_freed >= 2);
+        assert_eq!(ss.read_to_bytes("two", "big").await.unwrap(), edited);
+        ss.remove_index("two").await.unwrap();
+        ss.collect_garbage().await.unwrap();
+        let mut data_dir = fs::read_dir(td.path().join(DATA)).await.unwrap();
+        assert!(data_dir.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn compressed_storage() {
+        let text = "All work and no play makes Jack a dull boy.\n".repeat(100);
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let config = StorageConfig {
+            compression: Some(3),
+            ..Default::default()
+        };
+        let ss = SharedStorage::new_with_config(td.path().join("zst"), config)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        ss.import(
+            "one",
+            &mut provider,
+            stream::iter(file_events(&[("a", &text)])),
+        )
+        .await
+        .unwrap();
+        let identity = ss
+            .index(OsStr::new("one"))
+            .unwrap()
+            .dir
+            .file("a")
+            .unwrap()
+            .clone();
+        assert!(!identity.filename(&ss.base).exists());
+        let compressed = compressed_path(&ss.base, &identity);
+        assert!(std::fs::metadata(&compressed).unwrap().len() < 200);
+        assert_eq!(ss.read_to_bytes("one", "a").await.unwrap(), text);
+        let target = td.path().join("export");
+        ss.export_to_dir("one", &target, ExportMode::Hardlink)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(target.join("a")).unwrap(), text.as_bytes());
+
+        // Migrating a storage written without compression
+        let ss = SharedStorage::new(td.path().join("raw"))
+            .await
+            .expect("Unable to create storage");
+        ss.import(
+            "one",
+            &mut provider,
+            stream::iter(file_events(&[("a", &text), ("b", "short")])),
+        )
+        .await
+        .unwrap();
+        let report = ss.compress_blobs(3).await.unwrap();
+        assert_eq!(report.blobs_compressed, 2);
+        assert_eq!(report.bytes_before, text.len() as u64 + 5);
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!(ss.compress_blobs(3).await.unwrap().blobs_compressed, 0);
+        assert_eq!(ss.read_to_bytes("one", "a").await.unwrap(), text);
+        assert_eq!(ss.read_to_bytes("one", "b").await.unwrap(), "short");
+        ss.remove_index("one").await.unwrap();
+        assert_eq!(ss.collect_garbage().await.unwrap().blobs_freed, 2);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn fsck_and_repair() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let files = [("a", "alpha"), ("b", "bravo"), ("c", "charlie")];
+        ss.import("one", &mut provider, stream::iter(file_events(&files)))
+            .await
+            .unwrap();
+        assert_eq!(ss.fsck().await.unwrap(), FsckReport::default());
+
+        let dir = &ss.index(OsStr::new("one")).unwrap().dir;
+        let a = dir.file("a").unwrap().clone();
+        let b = dir.file("b").unwrap().clone();
+        std::fs::write(a.filename(&ss.base), "alpha!").unwrap();
+        std::fs::remove_file(b.filename(&ss.base)).unwrap();
+        let orphan = {
+            use sha2::{Digest, Sha256};
+            StorageIdentifier::new(format!("{:x}", Sha256::digest(b"odd")), 3, false)
+        };
+        std::fs::create_dir_all(orphan.filename(&ss.base).parent().unwrap()).unwrap();
+        std::fs::write(orphan.filename(&ss.base), "odd").unwrap();
+        let stray = td.path().join(DATA).join("incoming-1-1.tmp");
+        std::fs::write(&stray, "").unwrap();
+        let bad_index = td.path().join(INDICES).join("bad");
+        std::fs::write(&bad_index, "{ not an index").unwrap();
+
+        let report = ss.fsck().await.unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.missing, vec![b.clone()]);
+        assert_eq!(report.corrupt, vec![a.filename(&ss.base)]);
+        assert_eq!(report.orphans, vec![orphan.filename(&ss.base)]);
+        assert_eq!(report.stray_temporaries, vec![stray]);
+        assert_eq!(report.bad_indices, vec![bad_index]);
+
+        // Repopulating only some of the damage leaves the rest missing
+        let report = ss
+            .repair(&mut provider, stream::iter(file_events(&files[..1])))
+            .await
+            .unwrap();
+        assert_eq!(report.repaired, vec![a.clone()]);
+        assert_eq!(report.missing, vec![b.clone()]);
+        assert!(td
+            .path()
+            .join(QUARANTINE)
+            .join(a.filename(Path::new("")).strip_prefix(DATA).unwrap())
+            .exists());
+        assert_eq!(ss.read_to_bytes("one", "a").await.unwrap(), "alpha");
+        let report = ss
+            .repair(&mut provider, stream::iter(file_events(&files)))
+            .await
+            .unwrap();
+        assert_eq!(report.repaired, vec![b]);
+        assert!(report.missing.is_empty() && report.corrupt.is_empty());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn index_formats() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let mut legacy = Directory::default();
+        legacy
+            .insert_file("a", StorageIdentifier::new("a".repeat(64), 1, false))
+            .unwrap();
+        std::fs::create_dir_all(td.path().join(INDICES)).unwrap();
+        std::fs::write(
+            td.path().join(INDICES).join("old"),
+            String::try_from(&legacy).unwrap(),
+        )
+        .unwrap();
+        let mut flat = INDEX_MAGIC.to_vec();
+        flat.extend_from_slice(&FLAT_INDEX_VERSION.to_le_bytes());
+        bincode::DefaultOptions::new()
+            .serialize_into(&mut flat, &legacy)
+            .unwrap();
+        std::fs::write(td.path().join(INDICES).join("flat"), flat).unwrap();
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        assert_eq!(ss.index(OsStr::new("old")).unwrap().dir, legacy);
+        assert_eq!(ss.index(OsStr::new("flat")).unwrap().dir, legacy);
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        ss.import(
+            "new",
+            &mut provider,
+            stream::iter(file_events(&[("b", "b")])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ss.convert_indices().await.unwrap(), 2);
+        assert_eq!(ss.convert_indices().await.unwrap(), 0);
+        for name in &["old", "flat", "new"] {
+            let stored = std::fs::read(td.path().join(INDICES).join(name)).unwrap();
+            assert!(stored.starts_with(INDEX_MAGIC));
+        }
+
+        // Reloading the binary indices gives the same indices back
+        let config = StorageConfig {
+            max_index_size: Some(256),
+            ..Default::default()
+        };
+        let reloaded = SharedStorage::new_with_config(&td, config)
+            .await
+            .expect("Unable to reopen storage");
+        assert_eq!(reloaded.index(OsStr::new("old")).unwrap().dir, legacy);
+        assert_eq!(
+            reloaded.index(OsStr::new("new")).unwrap().dir,
+            ss.index(OsStr::new("new")).unwrap().dir
+        );
+        let names: Vec<_> = (0..20).map(|n| format!("file-{}", n)).collect();
+        let files: Vec<_> = names.iter().map(|n| (n.as_str(), "x")).collect();
+        assert!(matches!(
+            reloaded
+                .import("big", &mut provider, stream::iter(file_events(&files)))
+                .await,
+            Err(Error::IndexTooLarge(_, _))
+        ));
+        let config = StorageConfig {
+            max_index_size: Some(16),
+            ..Default::default()
+        };
+        assert!(matches!(
+            SharedStorage::new_with_config(&td, config).await,
+            Err(Error::IndexTooLarge(_, _))
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn shared_subtrees() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let tree = |top: &'static str| {
+            vec![
+                ImportEvent::Directory("lib".into()),
+                ImportEvent::File(Some("lib".into()), "big".into(), 3, false),
+                ImportEvent::FileData(Bytes::from_static(b"big")),
+                ImportEvent::File(None, "top".into(), top.len(), false),
+                ImportEvent::FileData(Bytes::from_static(top.as_bytes())),
+            ]
+        };
+        ss.import("one", &mut provider, stream::iter(tree("one")))
+            .await
+            .unwrap();
+        ss.import("two", &mut provider, stream::iter(tree("two")))
+            .await
+            .unwrap();
+        ss.import("three", &mut provider, stream::iter(tree("one")))
+            .await
+            .unwrap();
+        assert_eq!(
+            ss.index_digest("one").unwrap(),
+            ss.index_digest("three").unwrap()
+        );
+        assert_ne!(
+            ss.index_digest("one").unwrap(),
+            ss.index_digest("two").unwrap()
+        );
+        // The lib directory and the two distinct roots
+        let trees = scan_objects(&td.path().join(TREES)).await.unwrap();
+        assert_eq!(trees.len(), 3);
+        // Digests which are not hashes are corrupt, whatever their length
+        assert!(matches!(
+            read_node(td.path(), &"\u{e9}".repeat(32), None),
+            Err(Error::CorruptTree(_))
+        ));
+
+        let reloaded = SharedStorage::new(&td)
+            .await
+            .expect("Unable to reopen storage");
+        assert_eq!(
+            reloaded.index_digest("two").unwrap(),
+            ss.index_digest("two").unwrap()
+        );
+        assert_eq!(
+            reloaded.index(OsStr::new("two")).unwrap().dir,
+            ss.index(OsStr::new("two")).unwrap().dir
+        );
+        drop(reloaded);
+
+        ss.remove_index("one").await.unwrap();
+        assert_eq!(ss.collect_garbage().await.unwrap().trees_freed, 0);
+        ss.remove_index("two").await.unwrap();
+        let report = ss.collect_garbage().await.unwrap();
+        assert_eq!(report.trees_freed, 1);
+        assert_eq!(report.blobs_freed, 1);
+        ss.remove_index("three").await.unwrap();
+        assert_eq!(ss.collect_garbage().await.unwrap().trees_freed, 2);
+        assert!(matches!(
+            ss.index_digest("three"),
+            Err(Error::IndexNotFound(_))
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn reapi_trees() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let events = vec![
+            ImportEvent::Directory("bin".into()),
+            ImportEvent::File(Some("bin".into()), "tool".into(), 4, true),
+            ImportEvent::FileData(Bytes::from_static(b"tool")),
+            ImportEvent::File(None, "data".into(), 4, false),
+            ImportEvent::FileData(Bytes::from_static(b"data")),
+        ];
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let mut tree = ss.reapi_tree("one").unwrap();
+        ss.import_reapi_tree("two", &tree).await.unwrap();
+        assert_eq!(
+            ss.index_digest("one").unwrap(),
+            ss.index_digest("two").unwrap()
+        );
+
+        // The content of data is only stored as not executable
+        let root = tree.root.as_mut().unwrap();
+        root.files[0].is_executable = true;
+        ss.import_reapi_tree("three", &tree).await.unwrap();
+        let identity = ss
+            .index(OsStr::new("three"))
+            .unwrap()
+            .dir
+            .file("data")
+            .unwrap()
+            .clone();
+        assert!(identity.executable());
+        assert!(identity.filename(&ss.base).exists());
+        assert_eq!(ss.read_to_bytes("three", "data").await.unwrap(), "data");
+
+        let root = tree.root.as_mut().unwrap();
+        root.files[0].digest = Some(reapi::Digest {
+            hash: "f".repeat(64),
+            size_bytes: 4,
+        });
+        assert!(matches!(
+            ss.import_reapi_tree("four", &tree).await,
+            Err(Error::BlobNotFound(_))
+        ));
+        assert!(ss.indices().all(|n| n != "four"));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn shared_between_storages() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let one = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let two = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        one.import(
+            "a",
+            &mut provider,
+            stream::iter(file_events(&[("f", "content")])),
+        )
+        .await
+        .unwrap();
+        assert!(two.indices().all(|n| n != "a"));
+
+        // An index cannot be created if another storage already has
+        assert!(matches!(
+            two.merge("a", &[] as &[&str], MergePolicy::default()).await,
+            Err(Error::IndexExists(_))
+        ));
+        assert_eq!(two.refresh().await.unwrap(), 1);
+        assert_eq!(two.read_to_bytes("a", "f").await.unwrap(), "content");
+        assert_eq!(two.refresh().await.unwrap(), 0);
+
+        two.merge("b", &["a"], MergePolicy::default())
+            .await
+            .unwrap();
+        one.remove_index("a").await.unwrap();
+        assert_eq!(one.refresh().await.unwrap(), 1);
+        assert_eq!(one.read_to_bytes("b", "f").await.unwrap(), "content");
+
+        // Garbage collection notices indices removed by another storage
+        one.remove_index("b").await.unwrap();
+        let report = two.collect_garbage().await.unwrap();
+        assert_eq!(report.blobs_freed, 1);
+        assert_eq!(two.indices().count(), 0);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn concurrent_imports() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let imports: Vec<_> = (0..4)
+            .map(|n| {
+                let ss = ss.clone();
+                tokio::spawn(async move {
+                    let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+                    let name = format!("index{}", n);
+                    let content = format!("content {}", n);
+                    let events = file_events(&[("f", &content)]);
+                    ss.import(&name, &mut provider, stream::iter(events))
+                        .await
+                        .unwrap();
+                    assert_eq!(ss.read_to_bytes(&name, "f").await.unwrap(), content);
+                })
+            })
+            .collect();
+        for import in imports {
+            import.await.unwrap();
+        }
+        assert_eq!(ss.indices().count(), 4);
+        let reloaded = SharedStorage::new(&td).await.unwrap();
+        assert_eq!(reloaded.indices().count(), 4);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn leases() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let files = [("a", "alpha"), ("b", "bravo"), ("c", "charlie")];
+        for (name, content) in &files {
+            let events = file_events(&[("f", content), ("g", "shared")]);
+            ss.import(name, &mut provider, stream::iter(events))
+                .await
+                .unwrap();
+        }
+        let file = |name| {
+            ss.index(OsStr::new(name))
+                .unwrap()
+                .dir
+                .file("f")
+                .unwrap()
+                .clone()
+        };
+        let (b, c) = (file("b"), file("c"));
+        let hour = Duration::from_secs(3600);
+        let index_lease = ss
+            .lease(LeaseTarget::Index("a".into()), hour)
+            .await
+            .unwrap();
+        let blob_lease = ss
+            .lease(LeaseTarget::Identifiers(vec![b]), hour)
+            .await
+            .unwrap();
+        ss.lease(LeaseTarget::Identifiers(vec![c]), Duration::from_secs(0))
+            .await
+            .unwrap();
+        assert!(matches!(
+            ss.lease(LeaseTarget::Index("d".into()), hour).await,
+            Err(Error::IndexNotFound(_))
+        ));
+        for (name, _) in &files {
+            ss.remove_index(name).await.unwrap();
+        }
+
+        let report = ss.collect_garbage().await.unwrap();
+        assert_eq!(report.blobs_freed, 1);
+        assert_eq!(report.leases_expired, 1);
+        assert_eq!(report.trees_freed, 2);
+        let mut leases = ss.leases().await.unwrap();
+        leases.sort_by_key(|lease| lease.expires());
+        assert_eq!(leases, vec![index_lease.clone(), blob_lease.clone()]);
+
+        // Leases survive the storage being reopened
+        ss.release(&blob_lease).await.unwrap();
+        let ss = SharedStorage::new(&td).await.unwrap();
+        assert_eq!(ss.leases().await.unwrap(), vec![index_lease.clone()]);
+        assert_eq!(ss.collect_garbage().await.unwrap().blobs_freed, 1);
+        ss.release(&index_lease).await.unwrap();
+        ss.release(&index_lease).await.unwrap();
+        let report = ss.collect_garbage().await.unwrap();
+        assert_eq!(report.blobs_freed, 2);
+        assert_eq!(report.trees_freed, 1);
+
+        // Leased data is not orphaned, even once its index has gone
+        for (name, content) in &[("d", "delta"), ("e", "echo")] {
+            let events = file_events(&[("f", content)]);
+            ss.import(name, &mut provider, stream::iter(events))
+                .await
+                .unwrap();
+        }
+        let d = ss
+            .index(OsStr::new("d"))
+            .unwrap()
+            .dir
+            .file("f")
+            .unwrap()
+            .clone();
+        let e = ss.index(OsStr::new("e")).unwrap().digest.clone();
+        ss.lease(LeaseTarget::Identifiers(vec![d]), hour)
+            .await
+            .unwrap();
+        let tree_lease = ss
+            .lease(LeaseTarget::Index("e".into()), hour)
+            .await
+            .unwrap();
+        ss.remove_index("d").await.unwrap();
+        ss.remove_index("e").await.unwrap();
+        assert!(ss.fsck().await.unwrap().orphans.is_empty());
+
+        // Broken leases are reported rather than stopping collection
+        let garbage = td.path().join(LEASES).join("garbage");
+        std::fs::write(&garbage, b"garbage").unwrap();
+        std::fs::remove_file(tree_path(td.path(), &e)).unwrap();
+        let broken = vec![td.path().join(LEASES).join(tree_lease.id()), garbage];
+        assert_eq!(ss.fsck().await.unwrap().broken_leases, broken);
+        let report = ss.collect_garbage().await.unwrap();
+        assert_eq!(report.blobs_freed, 1);
+        assert_eq!(report.broken_leases, broken);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn quota_eviction() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let config = StorageConfig {
+            quota: Some(QuotaConfig {
+                limit: 2500,
+                low_water: 2000,
+            }),
+            ..StorageConfig::default()
+        };
+        let ss = SharedStorage::new_with_config(&td, config)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let contents: Vec<_> = ["a", "b", "c"].iter().map(|c| c.repeat(1000)).collect();
+        for (name, content) in ["a", "b"].iter().zip(&contents) {
+            let events = file_events(&[("f", content)]);
+            ss.import(name, &mut provider, stream::iter(events))
+                .await
+                .unwrap();
+        }
+        ss.read_to_bytes("a", "f").await.unwrap();
+        assert!(ss.last_used("a").await.unwrap() > ss.last_used("b").await.unwrap());
+
+        // Importing over the quota evicts the least recently used index
+        let events = file_events(&[("f", &contents[2])]);
+        ss.import("c", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let mut names: Vec<_> = ss.indices().collect();
+        names.sort();
+        assert_eq!(names, vec![OsString::from("a"), OsString::from("c")]);
+
+        // Leased indices are never evicted
+        let config = StorageConfig {
+            quota: Some(QuotaConfig {
+                limit: 1000,
+                low_water: 0,
+            }),
+            ..StorageConfig::default()
+        };
+        let ss = SharedStorage::new_with_config(&td, config).await.unwrap();
+        ss.lease(LeaseTarget::Index("a".into()), Duration::from_secs(3600))
+            .await
+            .unwrap();
+        let report = ss.enforce_quota().await.unwrap();
+        assert_eq!(report.indices_evicted, vec![OsString::from("c")]);
+        assert_eq!(report.garbage.blobs_freed, 1);
+        assert_eq!(report.bytes_used, 1000);
+        assert_eq!(ss.indices().collect::<Vec<_>>(), vec![OsString::from("a")]);
+        assert_eq!(
+            ss.enforce_quota().await.unwrap(),
+            EvictionReport {
+                bytes_used: 1000,
+                ..EvictionReport::default()
+            }
+        );
+
+        // Content which no index refers to yet is not removed by eviction,
+        // and temporary files are not counted towards the quota
+        let upload = StorageIdentifier::new("ab".repeat(32), 10, false);
+        let upload_path = upload.filename(td.path());
+        std::fs::create_dir_all(upload_path.parent().unwrap()).unwrap();
+        std::fs::write(&upload_path, "0123456789").unwrap();
+        std::fs::write(upload_path.with_extension("tmp"), "x".repeat(5000)).unwrap();
+        let events = file_events(&[("f", &contents[1])]);
+        ss.import("b", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        assert!(upload_path.exists());
+        assert_eq!(
+            ss.enforce_quota().await.unwrap(),
+            EvictionReport {
+                indices_evicted: vec![OsString::from("b")],
+                garbage: GarbageReport {
+                    blobs_freed: 1,
+                    bytes_freed: 1000,
+                    trees_freed: 1,
+                    ..GarbageReport::default()
+                },
+                bytes_used: 1010,
+                ..EvictionReport::default()
+            }
+        );
+        assert!(upload_path.exists());
+
+        // Action results count towards the quota, and are evicted least
+        // recently used first
+        let config = StorageConfig {
+            quota: Some(QuotaConfig {
+                limit: 1100,
+                low_water: 1060,
+            }),
+            ..StorageConfig::default()
+        };
+        let ss = SharedStorage::new_with_config(&td, config).await.unwrap();
+        let (first, second) = ("1".repeat(64), "2".repeat(64));
+        ss.write_action(&first, &[1; 50]).await.unwrap();
+        ss.write_action(&second, &[2; 50]).await.unwrap();
+        ss.read_action(&first).await.unwrap().unwrap();
+        let report = ss.enforce_quota().await.unwrap();
+        assert_eq!(report.actions_evicted, 1);
+        assert_eq!(report.bytes_used, 1060);
+        assert!(ss.read_action(&first).await.unwrap().is_some());
+        assert!(ss.read_action(&second).await.unwrap().is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn usage_stats() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let (a, b) = ("a".repeat(100), "b".repeat(50));
+        let events = file_events(&[("f", &a), ("g", "shared")]);
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let events = file_events(&[("f", &b), ("g", "shared"), ("h", &b)]);
+        ss.import("two", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+
+        let stats = ss.stats().await.unwrap();
+        assert_eq!((stats.blobs, stats.bytes), (3, 156));
+        let one = IndexStats {
+            logical_bytes: 106,
+            unique_bytes: 100,
+            shared_bytes: 6,
+        };
+        let two = IndexStats {
+            logical_bytes: 106,
+            unique_bytes: 50,
+            shared_bytes: 6,
+        };
+        assert_eq!(stats.indices[OsStr::new("one")], one);
+        assert_eq!(stats.indices[OsStr::new("two")], two);
+
+        // Data added behind the storage's back is only seen after a refresh
+        let stray = td.path().join(DATA).join("ff").join("ff");
+        std::fs::create_dir_all(&stray).unwrap();
+        std::fs::write(stray.join("stray"), "stray").unwrap();
+        assert_eq!(ss.stats().await.unwrap(), stats);
+        // Data the storage adds itself is counted without scanning again
+        let events = file_events(&[("f", "three")]);
+        ss.import("three", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let counted = ss.stats().await.unwrap();
+        assert_eq!((counted.blobs, counted.bytes), (4, 161));
+        ss.remove_index("three").await.unwrap();
+        ss.refresh().await.unwrap();
+        assert_eq!(ss.stats().await.unwrap().bytes, 166);
+
+        ss.remove_index("two").await.unwrap();
+        ss.collect_garbage().await.unwrap();
+        let stats = ss.stats().await.unwrap();
+        assert_eq!((stats.blobs, stats.bytes), (2, 106));
+        let one = IndexStats {
+            unique_bytes: 106,
+            shared_bytes: 0,
+            ..one
+        };
+        assert_eq!(
+            stats.indices.into_iter().collect::<Vec<_>>(),
+            vec![("one".into(), one)]
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn diff_indices() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let events = file_events(&[("kept", "kept"), ("old", "old"), ("tool", "v1")]);
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        let events = file_events(&[("kept", "kept"), ("new", "new"), ("tool", "v2")]);
+        ss.import("two", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+
+        assert_eq!(ss.diff("one", "one").await.unwrap().count(), 0);
+        let changes: Vec<_> = ss.diff("one", "two").await.unwrap().collect();
+        let paths: Vec<_> = changes.iter().map(Change::path).collect();
+        assert_eq!(
+            paths,
+            vec![Path::new("new"), Path::new("old"), Path::new("tool")]
+        );
+        assert!(matches!(&changes[0], Change::Added { .. }));
+        assert!(matches!(&changes[1], Change::Removed { .. }));
+        let (old, new) = (
+            changes[2].old_entry().unwrap(),
+            changes[2].new_entry().unwrap(),
+        );
+        assert_eq!(old.identity().unwrap().size(), 2);
+        assert_ne!(old, new);
+        assert!(matches!(
+            ss.diff("one", "three").await,
+            Err(Error::IndexNotFound(_))
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn derive_index() {
+        let td = tempfile::tempdir().expect("Unable to create tempdir");
+        let ss = SharedStorage::new(&td)
+            .await
+            .expect("Unable to create storage");
+        let mut provider = crate::util::SimpleResourceProvider::new(1, 1);
+        let events = file_events(&[("tool", "v1"), ("old", "old"), ("script", "run")]);
+        ss.import("one", &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+
+        let edits = [
+            IndexEdit::Delete("old".into()),
+            IndexEdit::Mkdir("empty/deeper".into()),
+            IndexEdit::SetExecutable("script".into(), true),
+        ];
+        let events = file_events(&[("tool", "v2"), ("new", "new")]);
+        ss.derive("one", "two", &edits, &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        assert_eq!(ss.read_to_bytes("two", "tool").await.unwrap(), "v2");
+        assert_eq!(ss.read_to_bytes("one", "tool").await.unwrap(), "v1");
+        assert_eq!(ss.read_to_bytes("two", "script").await.unwrap(), "run");
+        let two = ss.index(OsStr::new("two")).unwrap();
+        assert!(two.dir.file("script").unwrap().executable());
+        assert!(two.dir.traverse("empty/deeper").unwrap().is_empty());
+        let paths: Vec<_> = ss
+            .diff("one", "two")
+            .await
+            .unwrap()
+            .map(|change| change.path().to_owned())
+            .collect();
+        let expected = ["empty", "empty/deeper", "new", "old", "script", "tool"];
+        assert_eq!(
+            paths,
+            expected.iter().map(PathBuf::from).collect::<Vec<_>>()
+        );
+
+        // The new index is saved like any other
+        let reloaded = SharedStorage::new(&td).await.unwrap();
+        assert_eq!(reloaded.index_digest("two").unwrap(), two.digest);
+        assert!(reloaded.fsck().await.unwrap().is_clean());
+
+        // Content can be added to directories which are only in the base,
+        // and can link to files which are only in the base
+        let events = vec![
+            ImportEvent::File(Some("empty/deeper".into()), "added".into(), 5, false),
+            ImportEvent::FileData(Bytes::from_static(b"added")),
+            ImportEvent::HardLink(Some("empty".into()), "linked".into(), "script".into()),
+        ];
+        ss.derive("two", "three", &[], &mut provider, stream::iter(events))
+            .await
+            .unwrap();
+        assert_eq!(
+            ss.read_to_bytes("three", "empty/deeper/added")
+                .await
+                .unwrap(),
+            "added"
+        );
+        assert_eq!(ss.read_to_bytes("three", "tool").await.unwrap(), "v2");
+        let three = ss.index(OsStr::new("three")).unwrap();
+        assert_eq!(
+            three.dir.file("empty/linked").unwrap(),
+            two.dir.file("script").unwrap()
+        );
+
+        let edits = [IndexEdit::Delete("empty/missing".into())];
+        match ss
+            .derive("two", "four", &edits, &mut provider, stream::iter(vec![]))
+            .await
+        {
+            Err(Error::EntryNotFound(path)) => assert_eq!(path, Path::new("empty/missing")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        for (base, name) in &[("one", "two"), ("one", "one")] {
+            assert!(matches!(
+                ss.derive(base, name, &[], &mut provider, stream::iter(vec![]))
+                    .await,
+                Err(Error::IndexExists(_))
+            ));
+        }
+        assert_eq!(ss.indices().count(), 3);
     }
 }
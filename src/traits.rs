@@ -34,7 +34,7 @@ impl<T> ResourceClaimResult<T> {
 }
 
 #[async_trait]
-pub trait ResourceProvider {
+pub trait ResourceProvider: Send + Sync {
     /// The type of a claimed resource, basically opaque to caller
     /// It ought to implement Send and Sync or it'll probably go boom
     /// It must implement ResourceAllocation or the claim function won't work
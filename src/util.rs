@@ -1,20 +1,23 @@
 //! Useful utility stuff for shared storage
 //!
 
+use async_compression::tokio_02::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
 use async_trait::async_trait;
-use fehler::throws;
+use fehler::{throw, throws};
 use futures::future::BoxFuture;
 use futures::stream::unfold;
 use futures::Stream;
 use tokio::fs;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::Mutex;
 
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryFrom;
 use std::ffi::OsString;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
-use crate::storage::ImportEvent;
+use crate::storage::{ImportEvent, IMPORT_CHUNK_SIZE};
 use crate::{ResourceAllocation, ResourceClaimResult, ResourceProvider};
 
 type AMSRPInner = Arc<Mutex<SRPInner>>;
@@ -25,7 +28,8 @@ type AMSRPInner = Arc<Mutex<SRPInner>>;
 /// number of active claims during an import cycle.  The simple resource
 /// provider will hard-limit based on claim count.  It also makes sense to
 /// limit concurrent memory usage since importing into a storage requires that
-/// the data for the file is held in memory until it has been hashed.  Given
+/// the data for the file (or chunk of the file) is held in memory until it has
+/// been hashed.  Given
 /// that, the simple resource provider has a size limit.  However it's not
 /// ideal to hard-limit that since single claims might exceed the space requirements
 /// and so we soft-limit RAM so we can't exceed the space limit set unless
@@ -166,12 +170,14 @@ enum FSIMachine {
     Finished,
     Next(usize),
     Data(usize),
+    Chunks(usize, Option<fs::File>, u64),
 }
 
 #[derive(Debug)]
 enum FSEntry {
     Dir(PathBuf),
-    File(Option<PathBuf>, OsString, usize, bool),
+    File(Option<PathBuf>, OsString, u64, bool),
+    Symlink(Option<PathBuf>, OsString, OsString),
 }
 
 impl FSImportStream {
@@ -206,8 +212,7 @@ impl FSImportStream {
                     sub_path.pop();
                 } else if meta.is_file() {
                     let executable = is_executable(&meta);
-                    let len: usize = usize::try_from(meta.len())
-                        .expect("Cannot work with files bigger than virtual memory, sorry");
+                    let len = meta.len();
                     entries.push(FSEntry::File(
                         if sub_path.parent().is_some() {
                             Some(sub_path.clone())
@@ -218,12 +223,35 @@ impl FSImportStream {
                         len,
                         executable,
                     ))
+                } else if meta.file_type().is_symlink() {
+                    let target = fs::read_link(entry.path()).await?;
+                    entries.push(FSEntry::Symlink(
+                        if sub_path.parent().is_some() {
+                            Some(sub_path.clone())
+                        } else {
+                            None
+                        },
+                        entry.file_name(),
+                        target.into_os_string(),
+                    ))
                 }
             }
             Ok(())
         })
     }
 
+    fn file_path(&self, n: usize) -> Option<PathBuf> {
+        if let FSEntry::File(pd, fname, _, _) = &self.entries[n] {
+            Some(self.base_path.join(if let Some(pd) = pd {
+                pd.join(fname)
+            } else {
+                fname.into()
+            }))
+        } else {
+            None
+        }
+    }
+
     async fn next_event(mut self) -> Option<(ImportEvent, Self)> {
         use FSIMachine::*;
         loop {
@@ -238,12 +266,7 @@ impl FSImportStream {
                 }
                 Finished => None,
                 Data(n) => {
-                    if let FSEntry::File(pd, fname, _, _) = &self.entries[n] {
-                        let full_path = self.base_path.join(if let Some(pd) = pd {
-                            pd.join(fname)
-                        } else {
-                            fname.into()
-                        });
+                    if let Some(full_path) = self.file_path(n) {
                         let data = match fs::read(full_path).await {
                             Ok(data) => data,
                             Err(e) => return Some((ImportEvent::Error(e.into()), self)),
@@ -254,6 +277,29 @@ impl FSImportStream {
                         None
                     }
                 }
+                Chunks(n, _, 0) => {
+                    self.state = Next(n + 1);
+                    Some((ImportEvent::EndOfFile, self))
+                }
+                Chunks(n, fh, remaining) => {
+                    let mut fh = match fh {
+                        Some(fh) => fh,
+                        None => match self.file_path(n) {
+                            Some(full_path) => match fs::File::open(full_path).await {
+                                Ok(fh) => fh,
+                                Err(e) => return Some((ImportEvent::Error(e.into()), self)),
+                            },
+                            None => return None,
+                        },
+                    };
+                    let len = remaining.min(IMPORT_CHUNK_SIZE as u64);
+                    let mut data = vec![0; len as usize];
+                    if let Err(e) = fh.read_exact(&mut data).await {
+                        return Some((ImportEvent::Error(e.into()), self));
+                    }
+                    self.state = Chunks(n, Some(fh), remaining - len);
+                    Some((ImportEvent::FileData(data.into()), self))
+                }
                 Next(n) => {
                     if n == self.entries.len() {
                         None
@@ -263,10 +309,29 @@ impl FSImportStream {
                                 self.state = Next(n + 1);
                                 Some((ImportEvent::Directory(p.clone()), self))
                             }
-                            FSEntry::File(pd, fname, size, exec) => {
-                                self.state = Data(n);
+                            FSEntry::File(pd, fname, size, exec) => match usize::try_from(*size) {
+                                Ok(len) if len <= IMPORT_CHUNK_SIZE => {
+                                    self.state = Data(n);
+                                    Some((
+                                        ImportEvent::File(pd.clone(), fname.clone(), len, *exec),
+                                        self,
+                                    ))
+                                }
+                                _ => {
+                                    let event = ImportEvent::ChunkedFile(
+                                        pd.clone(),
+                                        fname.clone(),
+                                        *size,
+                                        *exec,
+                                    );
+                                    self.state = Chunks(n, None, *size);
+                                    Some((event, self))
+                                }
+                            },
+                            FSEntry::Symlink(pd, fname, target) => {
+                                self.state = Next(n + 1);
                                 Some((
-                                    ImportEvent::File(pd.clone(), fname.clone(), *size, *exec),
+                                    ImportEvent::Symlink(pd.clone(), fname.clone(), target.clone()),
                                     self,
                                 ))
                             }
@@ -282,6 +347,566 @@ impl FSImportStream {
     }
 }
 
+/// A tarball import stream usable with SharedStorage::import
+///
+/// The tarball is read sequentially from the reader, and file data is only
+/// read from the tarball when the FileData event is drawn from the stream.
+/// Tarballs do not need to contain entries for every directory, nor do they
+/// need to list directories before their content; any missing directories
+/// are inserted into the stream as needed.  GNU long names and PAX path and
+/// size extensions are honoured.  Hard links become files with the same
+/// content as the file they link to, which must come earlier in the tarball,
+/// and other entry types such as devices are skipped.
+pub struct TarImportStream<R> {
+    reader: R,
+    state: TIMachine,
+    pending: VecDeque<ImportEvent>,
+    known_dirs: HashSet<PathBuf>,
+    long_name: Option<Vec<u8>>,
+    long_link: Option<Vec<u8>>,
+    long_size: Option<u64>,
+}
+
+#[derive(Debug)]
+enum TIMachine {
+    Header,
+    Data(u64),
+    Chunks(u64, u64),
+    Finished,
+}
+
+const TAR_BLOCK: u64 = 512;
+
+impl<R> TarImportStream<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            state: TIMachine::Header,
+            pending: VecDeque::new(),
+            known_dirs: HashSet::new(),
+            long_name: None,
+            long_link: None,
+            long_size: None,
+        }
+    }
+
+    /// Read a header block, returning false if the reader is exhausted
+    #[throws(io::Error)]
+    async fn read_block(&mut self, block: &mut [u8; TAR_BLOCK as usize]) -> bool {
+        let mut got = 0;
+        while got < block.len() {
+            match self.reader.read(&mut block[got..]).await? {
+                0 if got == 0 => return false,
+                0 => throw!(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated header in tarball"
+                )),
+                n => got += n,
+            }
+        }
+        true
+    }
+
+    #[throws(io::Error)]
+    async fn read_data(&mut self, size: u64) -> Vec<u8> {
+        let len = usize::try_from(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "tarball entry too large"))?;
+        let mut data = vec![0; len];
+        self.reader.read_exact(&mut data).await?;
+        self.skip(padding(size)).await?;
+        data
+    }
+
+    #[throws(io::Error)]
+    async fn skip(&mut self, size: u64) {
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink()).await?;
+        if skipped != size {
+            throw!(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated entry in tarball"
+            ));
+        }
+    }
+
+    /// Queue up directory events for the given directory and any of its
+    /// parents which we have not yet seen
+    fn ensure_dir(&mut self, dir: &Path) {
+        let mut here = PathBuf::new();
+        for component in dir.components() {
+            here.push(component);
+            if self.known_dirs.insert(here.clone()) {
+                self.pending.push_back(ImportEvent::Directory(here.clone()));
+            }
+        }
+    }
+
+    /// Process the next header in the tarball, returning false at the end
+    #[throws(io::Error)]
+    async fn read_entry(&mut self) -> bool {
+        let mut block = [0u8; TAR_BLOCK as usize];
+        if !self.read_block(&mut block).await? || block.iter().all(|b| *b == 0) {
+            return false;
+        }
+        let header = tar::Header::from_byte_slice(&block);
+        let mut check = header.clone();
+        check.set_cksum();
+        if check.cksum()? != header.cksum()? {
+            throw!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad header checksum in tarball"
+            ));
+        }
+        let size = match header.entry_type() {
+            tar::EntryType::GNULongName | tar::EntryType::GNULongLink | tar::EntryType::XHeader => {
+                header.entry_size()?
+            }
+            // A PAX size replaces the size in the header of the next entry
+            _ => match self.long_size.take() {
+                Some(size) => size,
+                None => header.entry_size()?,
+            },
+        };
+        match header.entry_type() {
+            tar::EntryType::GNULongName | tar::EntryType::GNULongLink => {
+                let mut name = self.read_data(size).await?;
+                while name.last() == Some(&0) {
+                    name.pop();
+                }
+                if header.entry_type() == tar::EntryType::GNULongName {
+                    self.long_name = Some(name);
+                } else {
+                    self.long_link = Some(name);
+                }
+            }
+            tar::EntryType::XHeader => {
+                let data = self.read_data(size).await?;
+                for (key, value) in pax_records(&data)? {
+                    match key {
+                        b"path" => self.long_name = Some(value.to_vec()),
+                        b"linkpath" => self.long_link = Some(value.to_vec()),
+                        b"size" => {
+                            let size = std::str::from_utf8(value)
+                                .ok()
+                                .and_then(|size| size.parse().ok())
+                                .ok_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        "malformed PAX size in tarball",
+                                    )
+                                })?;
+                            self.long_size = Some(size);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            tar::EntryType::Directory => {
+                self.long_link = None;
+                let path = self.entry_path(header)?;
+                self.ensure_dir(&path);
+                self.skip(size + padding(size)).await?;
+            }
+            tar::EntryType::Symlink => {
+                let (parent, file_name) = self.entry_location(header)?;
+                let target = match self.long_link.take() {
+                    Some(link) => bytes_to_path(&link)?,
+                    None => bytes_to_path(&header.link_name_bytes().unwrap_or_default())?,
+                };
+                self.pending.push_back(ImportEvent::Symlink(
+                    parent,
+                    file_name,
+                    target.into_os_string(),
+                ));
+                self.skip(size + padding(size)).await?;
+            }
+            tar::EntryType::Link => {
+                let (parent, file_name) = self.entry_location(header)?;
+                let target = match self.long_link.take() {
+                    Some(link) => bytes_to_path(&link)?,
+                    None => bytes_to_path(&header.link_name_bytes().unwrap_or_default())?,
+                };
+                self.pending.push_back(ImportEvent::HardLink(
+                    parent,
+                    file_name,
+                    safe_path(&target)?,
+                ));
+                self.skip(size + padding(size)).await?;
+            }
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                self.long_link = None;
+                let (parent, file_name) = self.entry_location(header)?;
+                let executable = (header.mode()? & 0o111) != 0;
+                match usize::try_from(size) {
+                    Ok(len) if len <= IMPORT_CHUNK_SIZE => {
+                        self.pending
+                            .push_back(ImportEvent::File(parent, file_name, len, executable));
+                        self.state = TIMachine::Data(size);
+                    }
+                    _ => {
+                        self.pending.push_back(ImportEvent::ChunkedFile(
+                            parent, file_name, size, executable,
+                        ));
+                        self.state = TIMachine::Chunks(size, padding(size));
+                    }
+                }
+            }
+            _ => {
+                self.long_name = None;
+                self.long_link = None;
+                self.skip(size + padding(size)).await?;
+            }
+        }
+        true
+    }
+
+    /// Determine the path for an entry, consuming any long name we have
+    #[throws(io::Error)]
+    fn entry_path(&mut self, header: &tar::Header) -> PathBuf {
+        let raw = match self.long_name.take() {
+            Some(name) => bytes_to_path(&name)?,
+            None => bytes_to_path(&header.path_bytes())?,
+        };
+        safe_path(&raw)?
+    }
+
+    /// Determine the parent directory and name for a non-directory entry,
+    /// ensuring that the parent directory will exist in the index
+    #[throws(io::Error)]
+    fn entry_location(&mut self, header: &tar::Header) -> (Option<PathBuf>, OsString) {
+        let path = self.entry_path(header)?;
+        let file_name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            None => throw!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "entry without a name in tarball"
+            )),
+        };
+        let parent = path.parent().filter(|p| p.parent().is_some());
+        if let Some(parent) = parent {
+            self.ensure_dir(parent);
+        }
+        (parent.map(Path::to_owned), file_name)
+    }
+
+    async fn next_event(mut self) -> Option<(ImportEvent, Self)> {
+        use TIMachine::*;
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some((event, self));
+            }
+            break match std::mem::replace(&mut self.state, Finished) {
+                Finished => None,
+                Data(size) => match self.read_data(size).await {
+                    Ok(data) => {
+                        self.state = Header;
+                        Some((ImportEvent::FileData(data.into()), self))
+                    }
+                    Err(e) => Some((ImportEvent::Error(e.into()), self)),
+                },
+                Chunks(0, padding) => match self.skip(padding).await {
+                    Ok(()) => {
+                        self.state = Header;
+                        Some((ImportEvent::EndOfFile, self))
+                    }
+                    Err(e) => Some((ImportEvent::Error(e.into()), self)),
+                },
+                Chunks(remaining, padding) => {
+                    let len = remaining.min(IMPORT_CHUNK_SIZE as u64);
+                    let mut data = vec![0; len as usize];
+                    match self.reader.read_exact(&mut data).await {
+                        Ok(_) => {
+                            self.state = Chunks(remaining - len, padding);
+                            Some((ImportEvent::FileData(data.into()), self))
+                        }
+                        Err(e) => Some((ImportEvent::Error(e.into()), self)),
+                    }
+                }
+                Header => match self.read_entry().await {
+                    Ok(true) => {
+                        if let Finished = self.state {
+                            self.state = Header;
+                        }
+                        continue;
+                    }
+                    Ok(false) => None,
+                    Err(e) => Some((ImportEvent::Error(e.into()), self)),
+                },
+            };
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = ImportEvent> {
+        Box::pin(unfold(self, Self::next_event))
+    }
+}
+
+impl TarImportStream<Box<dyn AsyncRead + Unpin + Send>> {
+    /// Create a tarball import stream from a tarball which may be compressed.
+    ///
+    /// The compression format is detected from the magic bytes at the start of
+    /// the reader, and the tarball is decompressed as it is read.
+    #[throws(io::Error)]
+    pub async fn new_decompressing<R>(mut reader: R) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let mut magic = vec![0; Compression::MAGIC_LEN];
+        let mut got = 0;
+        while got < magic.len() {
+            match reader.read(&mut magic[got..]).await? {
+                0 => break,
+                n => got += n,
+            }
+        }
+        magic.truncate(got);
+        let compression = Compression::detect(&magic);
+        let reader = io::BufReader::new(std::io::Cursor::new(magic).chain(reader));
+        let reader: Box<dyn AsyncRead + Unpin + Send> = match compression {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(GzipDecoder::new(reader)),
+            Compression::Xz => Box::new(XzDecoder::new(reader)),
+            Compression::Zstd => Box::new(ZstdDecoder::new(reader)),
+        };
+        Self::new(reader)
+    }
+}
+
+/// A minimal tarball writer for exporting from storage
+///
+/// Entries are written with fixed ownership and timestamps so that the
+/// resulting tarball depends only on the content written to it.  Long paths
+/// are written using GNU long name entries.
+pub(crate) struct TarWriter<W> {
+    writer: W,
+}
+
+impl<W> TarWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub(crate) fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    #[throws(io::Error)]
+    async fn write_header(
+        &mut self,
+        path: &[u8],
+        link: &[u8],
+        entry_type: tar::EntryType,
+        mode: u32,
+        size: u64,
+    ) {
+        let header = tar_header(path, link, entry_type, mode, size);
+        if link.len() > header.as_old().linkname.len() {
+            self.write_long_name(tar::EntryType::GNULongLink, link)
+                .await?;
+        }
+        if path.len() > header.as_old().name.len() {
+            self.write_long_name(tar::EntryType::GNULongName, path)
+                .await?;
+        }
+        self.writer.write_all(header.as_bytes()).await?;
+    }
+
+    #[throws(io::Error)]
+    async fn write_long_name(&mut self, entry_type: tar::EntryType, name: &[u8]) {
+        let mut long_name = name.to_vec();
+        long_name.push(0);
+        let long_header = tar_header(
+            b"././@LongLink",
+            b"",
+            entry_type,
+            0o644,
+            long_name.len() as u64,
+        );
+        self.writer.write_all(long_header.as_bytes()).await?;
+        self.write_padded(&long_name).await?;
+    }
+
+    #[throws(io::Error)]
+    async fn write_padded(&mut self, data: &[u8]) {
+        self.writer.write_all(data).await?;
+        self.write_padding(data.len() as u64).await?;
+    }
+
+    #[throws(io::Error)]
+    async fn write_padding(&mut self, size: u64) {
+        let zeroes = [0u8; TAR_BLOCK as usize];
+        self.writer
+            .write_all(&zeroes[..padding(size) as usize])
+            .await?;
+    }
+
+    /// Append a directory entry, the path should not have a trailing slash
+    #[throws(io::Error)]
+    pub(crate) async fn append_dir(&mut self, path: &[u8]) {
+        let mut path = path.to_vec();
+        path.push(b'/');
+        self.write_header(&path, b"", tar::EntryType::Directory, 0o755, 0)
+            .await?;
+    }
+
+    /// Append a symbolic link entry pointing at the given target
+    #[throws(io::Error)]
+    pub(crate) async fn append_symlink(&mut self, path: &[u8], target: &[u8]) {
+        self.write_header(path, target, tar::EntryType::Symlink, 0o777, 0)
+            .await?;
+    }
+
+    /// Append a file entry whose content is drawn from the given reader
+    #[throws(io::Error)]
+    pub(crate) async fn append_file<R>(
+        &mut self,
+        path: &[u8],
+        size: u64,
+        executable: bool,
+        content: R,
+    ) where
+        R: AsyncRead + Unpin,
+    {
+        let mode = if executable { 0o755 } else { 0o644 };
+        self.write_header(path, b"", tar::EntryType::Regular, mode, size)
+            .await?;
+        let copied = io::copy(&mut content.take(size), &mut self.writer).await?;
+        if copied != size {
+            throw!(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "content shorter than expected when writing tarball"
+            ));
+        }
+        self.write_padding(size).await?;
+    }
+
+    /// Write the end of archive marker and flush the writer
+    #[throws(io::Error)]
+    pub(crate) async fn finish(mut self) -> W {
+        self.writer
+            .write_all(&[0u8; 2 * TAR_BLOCK as usize])
+            .await?;
+        self.writer.flush().await?;
+        self.writer
+    }
+}
+
+/// Prepare a tar header, truncating the path and link if they are too long
+fn tar_header(
+    path: &[u8],
+    link: &[u8],
+    entry_type: tar::EntryType,
+    mode: u32,
+    size: u64,
+) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    let name = &path[..path.len().min(header.as_old().name.len())];
+    header.as_old_mut().name[..name.len()].copy_from_slice(name);
+    let link = &link[..link.len().min(header.as_old().linkname.len())];
+    header.as_old_mut().linkname[..link.len()].copy_from_slice(link);
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(size);
+    header.set_cksum();
+    header
+}
+
+/// Compression formats which tarballs may be wrapped in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// The number of bytes needed to detect any supported compression
+    pub const MAGIC_LEN: usize = 6;
+
+    /// Detect the compression format from the first bytes of some data
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Self::Gzip
+        } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// How much padding follows an entry of the given size in a tarball
+fn padding(size: u64) -> u64 {
+    (TAR_BLOCK - (size % TAR_BLOCK)) % TAR_BLOCK
+}
+
+/// Parse the key/value records in a PAX extended header
+#[throws(io::Error)]
+fn pax_records(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let bad = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed PAX header in tarball",
+        )
+    };
+    let mut ret = Vec::new();
+    while !data.is_empty() {
+        // Each record is "LEN KEY=VALUE\n" where LEN includes itself
+        let space = data.iter().position(|b| *b == b' ').ok_or_else(bad)?;
+        let len: usize = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+            .filter(|l| *l > space && *l <= data.len())
+            .ok_or_else(bad)?;
+        let (newline, record) = data[space + 1..len].split_last().ok_or_else(bad)?;
+        if *newline != b'\n' {
+            throw!(bad());
+        }
+        let equals = record.iter().position(|b| *b == b'=').ok_or_else(bad)?;
+        ret.push((&record[..equals], &record[equals + 1..]));
+        data = &data[len..];
+    }
+    ret
+}
+
+#[cfg(not(windows))]
+#[throws(io::Error)]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+#[cfg(windows)]
+#[throws(io::Error)]
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    std::str::from_utf8(bytes)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "non-UTF-8 path in tarball"))?
+        .into()
+}
+
+/// Normalise a path from a tarball, refusing any which could escape it
+#[throws(io::Error)]
+fn safe_path(raw: &Path) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in raw.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(c) => path.push(c),
+            _ => throw!(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsafe path {:?} in tarball", raw)
+            )),
+        }
+    }
+    path
+}
+
 #[cfg(windows)]
 fn is_executable(_meta: &std::fs::Metadata) -> bool {
     false
@@ -389,6 +1014,8 @@ mod test {
             "This is another program file\n",
         )
         .await?;
+        #[cfg(not(windows))]
+        std::os::unix::fs::symlink("program", base_path.join("bin/link"))?;
         tdir
     }
 
@@ -402,10 +1029,21 @@ mod test {
         // Verify that we meet the rules of the stream
         let mut dirs = Vec::new();
         let mut files = Vec::new();
+        let mut links = Vec::new();
         let mut expecting_data = false;
         while let Some(event) = fstream.next().await {
             match event {
                 ImportEvent::Error(e) => panic!("{:?}", e),
+                ImportEvent::Symlink(pd, fname, target) => {
+                    if expecting_data {
+                        panic!("Got symlink entry when expecting file data!");
+                    }
+                    links.push((pd, fname, target));
+                }
+                ImportEvent::HardLink(..) => panic!("Got hard link from the filesystem!"),
+                ImportEvent::ChunkedFile(..) | ImportEvent::EndOfFile => {
+                    panic!("Got chunked file events for small files!");
+                }
                 ImportEvent::Directory(d) => {
                     if expecting_data {
                         panic!("Got directory {:?} when expecting file data", d);
@@ -439,6 +1077,11 @@ mod test {
         for f in &files {
             assert!(f.4.is_some());
         }
+        #[cfg(not(windows))]
+        assert_eq!(
+            links,
+            vec![(Some("bin".into()), "link".into(), "program".into())]
+        );
     }
 
     #[tokio::test(threaded_scheduler)]
@@ -449,11 +1092,376 @@ mod test {
             .unwrap()
             .into_stream();
         let storage_dir = get_tempdir("storage").await.unwrap();
-        let mut storage = crate::SharedStorage::new(storage_dir.path()).await.unwrap();
+        let storage = crate::SharedStorage::new(storage_dir.path()).await.unwrap();
         let mut linear_loader = crate::util::SimpleResourceProvider::new(1, 1);
         storage
             .import("test-index-1", &mut linear_loader, fstream)
             .await
             .unwrap();
     }
+
+    fn generate_tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, mode, entry_type, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(mode);
+            header.set_entry_type(entry_type);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("./bin/", 0o755, tar::EntryType::Directory, b"");
+        append(
+            "./bin/program",
+            0o755,
+            tar::EntryType::Regular,
+            b"This is a program file\n",
+        );
+        append(
+            "README",
+            0o644,
+            tar::EntryType::Regular,
+            b"This is the README file\n",
+        );
+        append(
+            "share/doc/README",
+            0o644,
+            tar::EntryType::Regular,
+            b"This is the README file\n",
+        );
+        append(
+            &format!("long/{}", "x".repeat(150)),
+            0o644,
+            tar::EntryType::Regular,
+            b"Long named\n",
+        );
+        append("bin/fifo", 0o644, tar::EntryType::Fifo, b"");
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder
+            .append_link(&mut header, "share/doc/link", "../../README")
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        header.set_mode(0o755);
+        builder
+            .append_link(&mut header, "share/program", "./bin/program")
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn check_tar_import_stream() {
+        let tarball = generate_tarball();
+        let mut tstream = TarImportStream::new(&tarball[..]).into_stream();
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        let mut links = Vec::new();
+        let mut hard_links = Vec::new();
+        let mut expecting_data = false;
+        while let Some(event) = tstream.next().await {
+            match event {
+                ImportEvent::Error(e) => panic!("{:?}", e),
+                ImportEvent::Symlink(pd, fname, target) => {
+                    assert!(!expecting_data);
+                    links.push((pd, fname, target));
+                }
+                ImportEvent::HardLink(pd, fname, target) => {
+                    assert!(!expecting_data);
+                    hard_links.push((pd, fname, target));
+                }
+                ImportEvent::ChunkedFile(..) | ImportEvent::EndOfFile => {
+                    panic!("Got chunked file events for small files!");
+                }
+                ImportEvent::Directory(d) => {
+                    assert!(!expecting_data);
+                    if let Some(parent) = d.parent().filter(|p| p.parent().is_some()) {
+                        assert!(dirs.contains(&parent.to_owned()));
+                    }
+                    dirs.push(d);
+                }
+                ImportEvent::File(pd, fname, size, exec) => {
+                    assert!(!expecting_data);
+                    if let Some(pd) = &pd {
+                        assert!(dirs.contains(pd));
+                    }
+                    files.push((pd, fname, size, exec));
+                    expecting_data = true;
+                }
+                ImportEvent::FileData(d) => {
+                    assert!(expecting_data);
+                    assert_eq!(d.len(), files[files.len() - 1].2);
+                    expecting_data = false;
+                }
+            }
+        }
+        assert_eq!(
+            dirs,
+            vec![
+                PathBuf::from("bin"),
+                PathBuf::from("share"),
+                PathBuf::from("share/doc"),
+                PathBuf::from("long"),
+            ]
+        );
+        assert_eq!(files.len(), 4);
+        assert_eq!(files[0].0, Some(PathBuf::from("bin")));
+        assert!(files[0].3);
+        assert_eq!(files[1].0, None);
+        assert!(!files[1].3);
+        assert_eq!(files[3].1.len(), 150);
+        assert_eq!(
+            links,
+            vec![(
+                Some("share/doc".into()),
+                "link".into(),
+                "../../README".into()
+            )]
+        );
+        assert_eq!(
+            hard_links,
+            vec![(Some("share".into()), "program".into(), "bin/program".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_tarball() {
+        let tarball = generate_tarball();
+        // Truncated part way through the data for bin/program
+        let truncated = &tarball[..1100];
+        let events: Vec<_> = TarImportStream::new(truncated)
+            .into_stream()
+            .collect()
+            .await;
+        assert!(matches!(events.last(), Some(ImportEvent::Error(_))));
+        // Corrupted header for bin/
+        let mut corrupt = tarball.clone();
+        corrupt[0] = b'X';
+        let events: Vec<_> = TarImportStream::new(&corrupt[..])
+            .into_stream()
+            .collect()
+            .await;
+        assert!(matches!(&events[..], [ImportEvent::Error(_)]));
+    }
+
+    #[tokio::test]
+    async fn pax_tarball() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let extend = |builder: &mut tar::Builder<Vec<u8>>, records: &[(&str, &str)]| {
+            let mut data = Vec::new();
+            for (key, value) in records {
+                // The length of each record includes the length itself
+                let body = key.len() + value.len() + 3;
+                let mut len = body;
+                while len != body + len.to_string().len() {
+                    len = body + len.to_string().len();
+                }
+                data.extend(format!("{} {}={}\n", len, key, value).bytes());
+            }
+            let mut header = tar::Header::new_ustar();
+            header.set_entry_type(tar::EntryType::XHeader);
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, "PaxHeader", &data[..])
+                .unwrap();
+        };
+        // The size in the header is wrong, as for an entry over 8 GiB
+        extend(&mut builder, &[("size", "600"), ("path", "pax/file")]);
+        let mut header = tar::Header::new_ustar();
+        header.set_size(0);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "short", &[b'x'; 600][..])
+            .unwrap();
+        // A link path given for a file is not used for a later link
+        extend(&mut builder, &[("linkpath", "stale")]);
+        let mut header = tar::Header::new_ustar();
+        header.set_size(2);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "plain", &b"hi"[..])
+            .unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "sym", "target").unwrap();
+        let tarball = builder.into_inner().unwrap();
+
+        let events: Vec<_> = TarImportStream::new(&tarball[..])
+            .into_stream()
+            .collect()
+            .await;
+        let mut events = events.into_iter();
+        assert!(matches!(events.next(), Some(ImportEvent::Directory(d)) if d == Path::new("pax")));
+        assert!(matches!(
+            events.next(),
+            Some(ImportEvent::File(Some(pd), name, 600, false)) if pd == Path::new("pax") && name == "file"
+        ));
+        assert!(
+            matches!(events.next(), Some(ImportEvent::FileData(d)) if d[..] == [b'x'; 600][..])
+        );
+        assert!(matches!(
+            events.next(),
+            Some(ImportEvent::File(None, name, 2, false)) if name == "plain"
+        ));
+        assert!(matches!(events.next(), Some(ImportEvent::FileData(_))));
+        assert!(matches!(
+            events.next(),
+            Some(ImportEvent::Symlink(None, name, target)) if name == "sym" && target == "target"
+        ));
+        assert!(events.next().is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn verify_tar_importing() {
+        let tarball = generate_tarball();
+        let storage_dir = get_tempdir("storage").await.unwrap();
+        let storage = crate::SharedStorage::new(storage_dir.path()).await.unwrap();
+        let mut linear_loader = crate::util::SimpleResourceProvider::new(1, 1);
+        storage
+            .import(
+                "test-tar-index",
+                &mut linear_loader,
+                TarImportStream::new(&tarball[..]).into_stream(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .read_to_bytes("test-tar-index", "share/program")
+                .await
+                .unwrap(),
+            "This is a program file\n"
+        );
+
+        // A hard link to a file which is not in the tarball is an error
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_size(0);
+        builder.append_link(&mut header, "link", "missing").unwrap();
+        let tarball = builder.into_inner().unwrap();
+        assert!(matches!(
+            storage
+                .import(
+                    "test-tar-broken-link",
+                    &mut linear_loader,
+                    TarImportStream::new(&tarball[..]).into_stream(),
+                )
+                .await,
+            Err(crate::Error::EntryNotFound(_))
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn verify_compressed_tar_importing() {
+        use async_compression::tokio_02::bufread::{GzipEncoder, XzEncoder, ZstdEncoder};
+        let tarball = generate_tarball();
+        let storage_dir = get_tempdir("storage").await.unwrap();
+        let storage = crate::SharedStorage::new(storage_dir.path()).await.unwrap();
+        let mut linear_loader = crate::util::SimpleResourceProvider::new(1, 1);
+        let mut compressed = vec![(Compression::None, tarball.clone())];
+        let mut data = Vec::new();
+        GzipEncoder::new(&tarball[..])
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        compressed.push((Compression::Gzip, data));
+        let mut data = Vec::new();
+        XzEncoder::new(&tarball[..])
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        compressed.push((Compression::Xz, data));
+        let mut data = Vec::new();
+        ZstdEncoder::new(&tarball[..])
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        compressed.push((Compression::Zstd, data));
+        for (compression, data) in compressed {
+            assert_eq!(Compression::detect(&data), compression);
+            let name = format!("{:?}", compression);
+            storage
+                .import_tar(&name, &mut linear_loader, std::io::Cursor::new(data))
+                .await
+                .unwrap();
+        }
+        assert_eq!(storage.indices().count(), 4);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn verify_chunked_importing() {
+        use sha2::{Digest, Sha256};
+        let content: Vec<u8> = (0..(IMPORT_CHUNK_SIZE * 2 + 100))
+            .map(|n| (n % 251) as u8)
+            .collect();
+        let tdir = get_tempdir("input").await.unwrap();
+        fs::write(tdir.path().join("big"), &content).await.unwrap();
+        let events: Vec<_> = FSImportStream::new(tdir.path())
+            .await
+            .unwrap()
+            .into_stream()
+            .collect()
+            .await;
+        assert!(matches!(&events[..], [
+            ImportEvent::ChunkedFile(None, _, size, false),
+            ImportEvent::FileData(_),
+            ImportEvent::FileData(_),
+            ImportEvent::FileData(last),
+            ImportEvent::EndOfFile,
+        ] if *size == content.len() as u64 && last.len() == 100));
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "big", &content[..])
+            .unwrap();
+        let tarball = builder.into_inner().unwrap();
+
+        let storage_dir = get_tempdir("storage").await.unwrap();
+        let storage = crate::SharedStorage::new(storage_dir.path()).await.unwrap();
+        // Claims for the whole file would be impossible
+        let mut chunk_loader = crate::util::SimpleResourceProvider::new_with_max_space(
+            1,
+            IMPORT_CHUNK_SIZE,
+            IMPORT_CHUNK_SIZE,
+        );
+        storage
+            .import(
+                "from-fs",
+                &mut chunk_loader,
+                FSImportStream::new(tdir.path())
+                    .await
+                    .unwrap()
+                    .into_stream(),
+            )
+            .await
+            .unwrap();
+        storage
+            .import(
+                "from-tar",
+                &mut chunk_loader,
+                TarImportStream::new(&tarball[..]).into_stream(),
+            )
+            .await
+            .unwrap();
+        for index in &["from-fs", "from-tar"] {
+            assert_eq!(
+                storage.read_to_bytes(index, "big").await.unwrap(),
+                &content[..]
+            );
+        }
+        let hash = format!("{:x}", Sha256::digest(&content));
+        let mut blob = storage_dir.path().join("data");
+        blob.push(&hash[0..2]);
+        blob.push(&hash[2..4]);
+        blob.push(format!("{}-{}", &hash[4..], content.len()));
+        assert!(blob.exists());
+    }
 }